@@ -0,0 +1,164 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use log::debug;
+use percent_encoding::percent_decode_str;
+
+use crate::config::expand_path;
+
+/// Supported archive formats, detected from the file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    const EXTENSIONS: [(&'static str, Format); 4] = [
+        (".tar.gz", Format::TarGz),
+        (".tgz", Format::TarGz),
+        (".tar", Format::Tar),
+        (".zip", Format::Zip),
+    ];
+
+    /// Detects the format of an archive from its name.
+    pub fn detect(name: &str) -> Option<Self> {
+        let lower = name.to_lowercase();
+        Self::EXTENSIONS
+            .iter()
+            .find(|(ext, _)| lower.ends_with(ext))
+            .map(|(_, f)| *f)
+    }
+}
+
+/// Returns the archive file name without its archive extension.
+pub fn stem(name: &str) -> &str {
+    let lower = name.to_lowercase();
+    for (ext, _) in Format::EXTENSIONS {
+        if lower.ends_with(ext) {
+            return name.get(..name.len() - ext.len()).unwrap_or(name);
+        }
+    }
+    name
+}
+
+/// Resolves an archive source value, which is either a local path or a `file://` URL.
+///
+/// Local paths are resolved with [`expand_path`]. URLs are percent-decoded, and only expanded if
+/// they do not contain an absolute path.
+pub fn resolve_path(value: &str) -> Result<PathBuf> {
+    let url = match value.split_once("://") {
+        Some(("file", url)) => url,
+        Some((scheme, _)) => bail!("unsupported archive URL scheme `{scheme}`"),
+        None => return expand_path(value),
+    };
+
+    let url = url.strip_prefix("localhost").unwrap_or(url);
+    let path = percent_decode_str(url)
+        .decode_utf8()
+        .with_context(|| format!("invalid archive URL `{value}`"))?;
+    if Path::new(path.as_ref()).is_absolute() {
+        Ok(PathBuf::from(path.as_ref()))
+    } else {
+        expand_path(&path)
+    }
+}
+
+/// Extracts the archive at `path` into `dest`.
+///
+/// The archive is extracted into a temporary directory next to `dest` and renamed once complete,
+/// replacing any previous content. If the archive contains a single top-level directory, as is
+/// common for release tarballs, its contents are used instead.
+pub fn extract(path: &Path, dest: &Path) -> Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let Some(format) = Format::detect(&name) else {
+        bail!(
+            "unsupported archive `{}`, expected .tar, .tar.gz, .tgz, or .zip",
+            path.display()
+        );
+    };
+
+    let parent = dest
+        .parent()
+        .with_context(|| format!("path `{}` has no parent directory", dest.display()))?;
+    std::fs::create_dir_all(parent)?;
+    let dest_name = dest.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = parent.join(format!(".{dest_name}.dfim-{}.tmp", std::process::id()));
+    if tmp.exists() {
+        std::fs::remove_dir_all(&tmp)?;
+    }
+
+    debug!("Extracting `{}` into `{}`", path.display(), dest.display());
+    let result = unpack(path, format, &tmp).and_then(|_| {
+        let root = single_dir(&tmp)?.unwrap_or_else(|| tmp.clone());
+        if dest.exists() {
+            std::fs::remove_dir_all(dest)?;
+        }
+        std::fs::rename(&root, dest)?;
+        Ok(())
+    });
+    if tmp.exists() {
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
+
+    result.with_context(|| format!("failed to extract `{}`", path.display()))
+}
+
+fn unpack(path: &Path, format: Format, dest: &Path) -> Result<()> {
+    let file = BufReader::new(
+        File::open(path).with_context(|| format!("failed to open `{}`", path.display()))?,
+    );
+    std::fs::create_dir_all(dest)?;
+
+    match format {
+        Format::Tar => tar::Archive::new(file).unpack(dest)?,
+        Format::TarGz => tar::Archive::new(GzDecoder::new(file)).unpack(dest)?,
+        Format::Zip => zip::ZipArchive::new(file)?.extract(dest)?,
+    }
+    Ok(())
+}
+
+/// Returns the only entry in `dir` if it is a directory.
+fn single_dir(dir: &Path) -> Result<Option<PathBuf>> {
+    let entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    match entries.as_slice() {
+        [entry] if entry.file_type()?.is_dir() => Ok(Some(entry.path())),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_format() {
+        assert_eq!(Format::detect("foo.tar.gz"), Some(Format::TarGz));
+        assert_eq!(Format::detect("foo.TGZ"), Some(Format::TarGz));
+        assert_eq!(Format::detect("foo.tar"), Some(Format::Tar));
+        assert_eq!(Format::detect("foo.zip"), Some(Format::Zip));
+        assert_eq!(Format::detect("foo.7z"), None);
+    }
+
+    #[test]
+    fn stem_strips_extension() {
+        assert_eq!(stem("dotfiles-1.0.tar.gz"), "dotfiles-1.0");
+        assert_eq!(stem("dotfiles.zip"), "dotfiles");
+        assert_eq!(stem("dotfiles"), "dotfiles");
+    }
+
+    #[test]
+    fn resolve_file_url() {
+        let p = resolve_path("file:///srv/dotfiles.tar.gz").unwrap();
+        assert_eq!(p, PathBuf::from("/srv/dotfiles.tar.gz"));
+        let p = resolve_path("file://localhost/srv/my%20dotfiles.tar.gz").unwrap();
+        assert_eq!(p, PathBuf::from("/srv/my dotfiles.tar.gz"));
+        assert!(resolve_path("https://example.com/dotfiles.zip").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use std::ops::Range;
+
+use anyhow::{bail, Result};
+
+/// Comment prefix for block markers when a layer does not set one.
+pub const DEFAULT_COMMENT: &str = "#";
+
+/// Returns the line that starts the block `name`, without the comment prefix.
+fn begin_marker(name: &str) -> String {
+    format!(">>> dfim:{name} >>>")
+}
+
+/// Returns the line that ends the block `name`, without the comment prefix.
+fn end_marker(name: &str) -> String {
+    format!("<<< dfim:{name} <<<")
+}
+
+/// Returns the byte range of each line in `text`, including its line break.
+fn lines(text: &str) -> impl Iterator<Item = Range<usize>> + '_ {
+    let mut start = 0;
+    text.split_inclusive('\n').map(move |line| {
+        let range = start..start + line.len();
+        start = range.end;
+        range
+    })
+}
+
+/// Returns `true` if `line` is the marker `marker`, behind any comment prefix.
+fn is_marker(line: &str, marker: &str) -> bool {
+    line.trim_end().ends_with(marker)
+}
+
+/// Locates the block `name` in `text`, returning the range of the whole block including its
+/// markers, and the range of its body.
+///
+/// Markers are matched regardless of their comment prefix, so blocks can be found without knowing
+/// how they were written.
+fn locate(text: &str, name: &str) -> Result<Option<(Range<usize>, Range<usize>)>> {
+    let (begin, end) = (begin_marker(name), end_marker(name));
+    let mut lines = lines(text);
+
+    let Some(first) = lines.find(|l| is_marker(&text[l.clone()], &begin)) else {
+        return Ok(None);
+    };
+    let Some(last) = lines.find(|l| is_marker(&text[l.clone()], &end)) else {
+        bail!("block `dfim:{name}` is missing its end marker `{end}`");
+    };
+
+    Ok(Some((first.start..last.end, first.end..last.start)))
+}
+
+/// Returns the body of the block `name` in `text`, or `None` if there is no such block.
+pub fn find<'a>(text: &'a str, name: &str) -> Result<Option<&'a str>> {
+    Ok(locate(text, name)?.map(|(_, body)| &text[body]))
+}
+
+/// Returns `content` as it appears inside of a block, which always ends with a line break.
+pub fn body(content: &str) -> String {
+    let mut body = content.to_owned();
+    if !body.is_empty() && !body.ends_with('\n') {
+        body.push('\n');
+    }
+    body
+}
+
+/// Replaces the body of the block `name` in `text`, or appends the block if there is none.
+///
+/// Everything outside of the block is kept as is. When `text` does not end with a line break, the
+/// block is appended on a new line and does not end with a line break either, so that [`remove`]
+/// can restore the original text.
+pub fn insert(text: &str, name: &str, comment: &str, content: &str) -> Result<String> {
+    let mut block = format!(
+        "{comment} {}\n{}{comment} {}\n",
+        begin_marker(name),
+        body(content),
+        end_marker(name)
+    );
+
+    let mut result = text.to_owned();
+    match locate(text, name)? {
+        Some((range, _)) => {
+            if !text[range.clone()].ends_with('\n') {
+                block.pop();
+            }
+            result.replace_range(range, &block)
+        }
+        None => {
+            if !result.is_empty() && !result.ends_with('\n') {
+                result.push('\n');
+                block.pop();
+            }
+            result.push_str(&block);
+        }
+    }
+    Ok(result)
+}
+
+/// Removes the block `name` from `text`, including its markers and the line break added by
+/// [`insert`].
+///
+/// Returns `None` if there is no such block.
+pub fn remove(text: &str, name: &str) -> Result<Option<String>> {
+    Ok(locate(text, name)?.map(|(mut range, _)| {
+        if !text[range.clone()].ends_with('\n') && text[..range.start].ends_with('\n') {
+            range.start -= 1;
+        }
+        let mut result = text.to_owned();
+        result.replace_range(range, "");
+        result
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str =
+        "export A=1\n# >>> dfim:shell >>>\nalias l=ls\n# <<< dfim:shell <<<\nexport B=2\n";
+
+    #[test]
+    fn find_block() {
+        assert_eq!(find(TEXT, "shell").unwrap(), Some("alias l=ls\n"));
+        assert_eq!(find(TEXT, "other").unwrap(), None);
+        assert!(find("-- >>> dfim:x >>>\nfoo\n", "x").is_err());
+    }
+
+    #[test]
+    fn insert_block() {
+        let text = insert(TEXT, "shell", "#", "alias ll='ls -l'").unwrap();
+        assert_eq!(
+            text,
+            "export A=1\n# >>> dfim:shell >>>\nalias ll='ls -l'\n# <<< dfim:shell <<<\nexport B=2\n"
+        );
+        let text = insert("set nu", "vim", "\"", "set rnu\n").unwrap();
+        assert_eq!(
+            text,
+            "set nu\n\" >>> dfim:vim >>>\nset rnu\n\" <<< dfim:vim <<<"
+        );
+        assert_eq!(find(&text, "vim").unwrap(), Some("set rnu\n"));
+        let text = insert(&text, "vim", "\"", "set list").unwrap();
+        assert_eq!(
+            text,
+            "set nu\n\" >>> dfim:vim >>>\nset list\n\" <<< dfim:vim <<<"
+        );
+    }
+
+    #[test]
+    fn remove_block() {
+        let text = remove(TEXT, "shell").unwrap();
+        assert_eq!(text.as_deref(), Some("export A=1\nexport B=2\n"));
+        assert_eq!(remove(TEXT, "other").unwrap(), None);
+
+        for original in ["", "set nu", "set nu\n"] {
+            let text = insert(original, "vim", "\"", "set rnu").unwrap();
+            assert_eq!(remove(&text, "vim").unwrap().as_deref(), Some(original));
+        }
+    }
+}
@@ -1,8 +1,10 @@
-use std::path::PathBuf;
+use std::{io::IsTerminal, path::PathBuf};
 
-use clap::{Args, ColorChoice, Parser, Subcommand};
+use clap::{Args, ColorChoice, Parser, Subcommand, ValueEnum};
 use log::Level;
 
+use crate::layer::ConflictPolicy;
+
 static NAME: &str = env!("CARGO_BIN_NAME");
 
 static AFTER_HELP: &str = "Use -h for short descriptions and --help for more details";
@@ -23,12 +25,18 @@ static AFTER_HELP: &str = "Use -h for short descriptions and --help for more det
 pub struct Cli {
     #[command(subcommand)]
     pub command: Option<Commands>,
+    /// Answer prompts from a JSON file instead of asking interactively
+    #[arg(long, value_name = "PATH", global = true)]
+    pub answers: Option<PathBuf>,
     /// Specify when to use color output
     #[arg(long, global = true)]
     pub color: Option<ColorChoice>,
     /// Override the configuration file path
     #[arg(long = "config", value_name = "PATH", global = true)]
     pub config_path: Option<PathBuf>,
+    /// Require repo sources to match the commits in the lockfile
+    #[arg(long, global = true)]
+    pub locked: bool,
     /// Set logging output level (trace, debug, info, warn, error)
     #[arg(long, value_name = "LEVEL", global = true)]
     pub log_level: Option<Level>,
@@ -47,6 +55,15 @@ pub struct Cli {
 }
 
 impl Cli {
+    /// Returns `true` if color should be used when writing to `stream`.
+    pub fn use_color<S: IsTerminal>(&self, stream: &S) -> bool {
+        match self.color.unwrap_or(ColorChoice::Auto) {
+            ColorChoice::Auto => stream.is_terminal(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+
     pub fn version_string(&self) -> String {
         let mut value = vec![];
 
@@ -86,13 +103,181 @@ impl Cli {
 
 #[derive(Debug, Subcommand, Clone)]
 pub enum Commands {
+    /// Replace sources with existing targets that are not managed by dfim
+    Adopt(AdoptArgs),
+    /// Deploy layers to the home directory
+    Apply(ApplyArgs),
+    /// Remove deployed targets that are no longer managed by any layer
+    Clean(CleanArgs),
+    /// Show unified diffs of changes apply would make
+    Diff(DiffArgs),
     /// Execute lua by block, file, or in a basic REPL
     Lua(LuaArgs),
+    /// Render a template to stdout
+    Render(RenderArgs),
+    /// Undo the changes made by the last apply
+    Rollback(RollbackArgs),
+    /// List, sync, and inspect sources
+    Sources(SourcesArgs),
+    /// Show differences between sources and deployed targets
+    Status(StatusArgs),
+    /// Remove deployed targets
+    Uninstall(UninstallArgs),
+    /// Show which layer deploys a target and which layers it shadows
+    Which(WhichArgs),
     /// Show version information
     #[command(hide = true)]
     Version,
 }
 
+#[derive(Debug, Clone, Args)]
+pub struct PlanArgs {
+    /// Show planned operations without executing them
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+    /// Output format for the plan
+    #[arg(long, value_name = "FORMAT", default_value_t, value_enum)]
+    pub format: OutputFormat,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ApplyArgs {
+    /// Only apply the given layers (defaults to all layers)
+    #[arg(value_name = "LAYER")]
+    pub layers: Vec<String>,
+    /// How to handle existing targets not managed by dfim (overrides the config)
+    #[arg(long, value_name = "POLICY", value_enum)]
+    pub on_conflict: Option<ConflictPolicy>,
+    #[command(flatten)]
+    pub plan: PlanArgs,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct AdoptArgs {
+    /// Only adopt targets from the given layers (defaults to all layers)
+    #[arg(value_name = "LAYER")]
+    pub layers: Vec<String>,
+    #[command(flatten)]
+    pub plan: PlanArgs,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct CleanArgs {
+    #[command(flatten)]
+    pub plan: PlanArgs,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct UninstallArgs {
+    /// Only remove targets from the given layers (defaults to all layers)
+    #[arg(value_name = "LAYER")]
+    pub layers: Vec<String>,
+    #[command(flatten)]
+    pub plan: PlanArgs,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct RollbackArgs {
+    /// List recorded generations instead of rolling back
+    #[arg(long, conflicts_with_all = ["dry_run", "format"])]
+    pub list: bool,
+    /// Remove generations beyond the `keep_generations` option, along with their backups
+    #[arg(long, conflicts_with_all = ["list", "dry_run", "format"])]
+    pub prune: bool,
+    #[command(flatten)]
+    pub plan: PlanArgs,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct DiffArgs {
+    /// Only show changes for the given layers (defaults to all layers)
+    #[arg(value_name = "LAYER")]
+    pub layers: Vec<String>,
+    /// How to handle existing targets not managed by dfim (overrides the config)
+    #[arg(long, value_name = "POLICY", value_enum)]
+    pub on_conflict: Option<ConflictPolicy>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable table
+    #[default]
+    Table,
+    /// JSON document
+    Json,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct StatusArgs {
+    /// Output format
+    #[arg(long, value_name = "FORMAT", default_value_t, value_enum)]
+    pub format: OutputFormat,
+    /// Exit with a failure code if any target is not up to date
+    #[arg(long)]
+    pub exit_code: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct SourcesArgs {
+    #[command(subcommand)]
+    pub command: SourcesCommand,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum SourcesCommand {
+    /// List registered sources
+    List {
+        /// Output format
+        #[arg(long, value_name = "FORMAT", default_value_t, value_enum)]
+        format: OutputFormat,
+    },
+    /// Clone or update repo sources
+    Sync {
+        /// Only sync the given sources (defaults to all sources)
+        #[arg(value_name = "SOURCE")]
+        names: Vec<String>,
+    },
+    /// Show details about a source
+    Info {
+        /// Name of the source
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct RenderArgs {
+    /// Path of the template file
+    pub file: PathBuf,
+    /// Set a template variable, overriding the config (can be used multiple times)
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_var)]
+    pub vars: Vec<(String, String)>,
+    /// Render as if running on another operating system (e.g. linux, macos, windows)
+    #[arg(long, value_name = "OS")]
+    pub os: Option<String>,
+    /// Render as if running on another architecture (e.g. x86_64, aarch64)
+    #[arg(long, value_name = "ARCH")]
+    pub arch: Option<String>,
+    /// Render as if running on another host
+    #[arg(long, value_name = "NAME")]
+    pub hostname: Option<String>,
+}
+
+fn parse_var(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((k, v)) if !k.is_empty() => Ok((k.to_owned(), v.to_owned())),
+        _ => Err(format!("expected KEY=VALUE, got `{value}`")),
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct WhichArgs {
+    /// Path of the deployed target
+    pub target: PathBuf,
+    /// Output format
+    #[arg(long, value_name = "FORMAT", default_value_t, value_enum)]
+    pub format: OutputFormat,
+}
+
 #[derive(Debug, Clone, Args)]
 pub struct LuaArgs {
     /// Execute a block of lua code
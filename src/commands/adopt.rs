@@ -0,0 +1,15 @@
+use anyhow::Result;
+
+use crate::{
+    cli::{AdoptArgs, Cli},
+    plan,
+    state::State,
+};
+
+pub fn exec(args: &AdoptArgs, cli: &Cli) -> Result<()> {
+    let (_lua, _, files) = super::load_managed_files(&args.layers, true)?;
+    let state = State::load()?;
+    let plan = plan::adopt(&files, &state)?;
+
+    super::run_plan(&plan, state, Some("adopt"), &args.plan, cli)
+}
@@ -0,0 +1,26 @@
+use anyhow::Result;
+
+use crate::{
+    cli::{ApplyArgs, Cli},
+    config::Config,
+    plan::{self, ApplyOptions},
+    state::State,
+};
+
+pub fn exec(args: &ApplyArgs, cli: &Cli) -> Result<()> {
+    let (lua, layers, files) = super::load_managed_files(&args.layers, true)?;
+    let config = Config::from_lua(&lua)?;
+    let state = State::load()?;
+    let options = ApplyOptions {
+        on_conflict: config.on_conflict,
+        force_conflict: args.on_conflict,
+        backup_dir: plan::new_backup_dir(),
+    };
+    let plan = plan::apply(&layers, &files, &state, &options)?;
+
+    super::run_plan(&plan, state, Some("apply"), &args.plan, cli)?;
+    if !args.plan.dry_run {
+        crate::lua::save_data(&lua)?;
+    }
+    Ok(())
+}
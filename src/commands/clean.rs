@@ -0,0 +1,25 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+
+use crate::{
+    cli::{CleanArgs, Cli},
+    plan,
+    state::State,
+    status,
+};
+
+pub fn exec(args: &CleanArgs, cli: &Cli) -> Result<()> {
+    let (_lua, _, files) = super::load_managed_files(&[], true)?;
+    let managed = files
+        .iter()
+        .map(|f| status::key(&f.target, f.mode, &f.layer))
+        .collect::<HashSet<_>>();
+
+    let state = State::load()?;
+    let plan = plan::remove(&state, |target, entry| {
+        !managed.contains(&status::key(target, entry.mode, &entry.layer))
+    })?;
+
+    super::run_plan(&plan, state, Some("clean"), &args.plan, cli)
+}
@@ -0,0 +1,299 @@
+use std::{
+    io::{stdout, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use indexmap::IndexMap;
+use similar::{ChangeTag, TextDiff};
+
+use crate::{
+    block,
+    cli::{Cli, DiffArgs},
+    config::Config,
+    deploy,
+    plan::{self, ApplyOptions, Operation, Plan},
+    state::State,
+};
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+
+pub fn exec(args: &DiffArgs, cli: &Cli) -> Result<()> {
+    let (lua, layers, files) = super::load_managed_files(&args.layers, false)?;
+    let config = Config::from_lua(&lua)?;
+    let state = State::load()?;
+    let options = ApplyOptions {
+        on_conflict: config.on_conflict,
+        force_conflict: args.on_conflict,
+        backup_dir: plan::new_backup_dir(),
+    };
+    let plan = plan::apply(&layers, &files, &state, &options)?;
+    let targets = simulate(&plan)?;
+    if cli.quiet {
+        return Ok(());
+    }
+
+    let color = cli.use_color(&stdout());
+    let mut out = stdout().lock();
+    for (path, target) in &targets {
+        if target.old != target.new {
+            write_diff(&mut out, path, target, color)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The content of a path, and the value of the link if it is a symlink.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Content {
+    link: Option<PathBuf>,
+    /// Content of the file, or of the file a link points to. `None` if there is no such file.
+    data: Option<Vec<u8>>,
+}
+
+impl Content {
+    fn read(path: &Path) -> Result<Self> {
+        let meta = match std::fs::symlink_metadata(path) {
+            Ok(m) => m,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read `{}`", path.display()))
+            }
+        };
+        let link = match meta.is_symlink() {
+            true => Some(std::fs::read_link(path)?),
+            false => None,
+        };
+        let data = match std::fs::read(path) {
+            Ok(c) => Some(c),
+            // broken links and directories have no content to compare
+            Err(_) if link.is_some() || meta.is_dir() => None,
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read `{}`", path.display()))
+            }
+        };
+        Ok(Self { link, data })
+    }
+}
+
+/// A path changed by a plan, with its content before and after the plan is executed.
+#[derive(Debug)]
+struct Target {
+    layer: Option<String>,
+    old: Content,
+    new: Content,
+}
+
+/// Works out the content of every path touched by `plan` once it is executed, without changing
+/// anything.
+fn simulate(plan: &Plan) -> Result<IndexMap<PathBuf, Target>> {
+    let mut targets = IndexMap::new();
+    for step in &plan.steps {
+        for op in &step.operations {
+            match op {
+                Operation::WriteFile {
+                    path,
+                    source,
+                    content,
+                    ..
+                } => {
+                    let data = match content {
+                        Some(c) => c.clone(),
+                        None => read_source(source)?,
+                    };
+                    *touch(&mut targets, path, &step.layer)? = Content {
+                        link: None,
+                        data: Some(data),
+                    };
+                }
+                Operation::WriteBlock {
+                    path,
+                    source,
+                    name,
+                    comment,
+                    content,
+                    ..
+                } => {
+                    let body = match content {
+                        Some(c) => c.clone(),
+                        None => read_source(source)?,
+                    };
+                    let body = String::from_utf8(body)
+                        .with_context(|| format!("`{}` is not valid UTF-8", source.display()))?;
+                    let current = touch(&mut targets, path, &step.layer)?;
+                    let text = String::from_utf8_lossy(current.data.as_deref().unwrap_or_default());
+                    let text = block::insert(&text, name, comment, &body)?;
+                    current.data = Some(text.into_bytes());
+                }
+                Operation::CreateLink { path, target } => {
+                    let resolved = match path.parent() {
+                        Some(parent) => parent.join(target),
+                        None => target.clone(),
+                    };
+                    *touch(&mut targets, path, &step.layer)? = Content {
+                        link: Some(target.clone()),
+                        data: std::fs::read(resolved).ok(),
+                    };
+                }
+                Operation::Remove { path } | Operation::Backup { path, .. } => {
+                    *touch(&mut targets, path, &step.layer)? = Content::default();
+                }
+                Operation::RemoveBlock { path, name } => {
+                    let current = touch(&mut targets, path, &step.layer)?;
+                    let text = String::from_utf8_lossy(current.data.as_deref().unwrap_or_default());
+                    if let Some(text) = block::remove(&text, name)? {
+                        current.data = (!text.is_empty()).then(|| text.into_bytes());
+                    }
+                }
+                Operation::Adopt { path, source } => {
+                    let data = touch(&mut targets, path, &step.layer)?.data.clone();
+                    touch(&mut targets, source, &step.layer)?.data = data;
+                }
+                Operation::Restore { path, backup } => {
+                    *touch(&mut targets, path, &step.layer)? = Content::read(backup)?;
+                }
+                Operation::CreateDir { .. }
+                | Operation::RemoveDir { .. }
+                | Operation::Chmod { .. }
+                | Operation::RunHook { .. }
+                | Operation::Record { .. }
+                | Operation::Forget { .. }
+                | Operation::ForgetBlock { .. } => {}
+            }
+        }
+    }
+
+    Ok(targets)
+}
+
+/// Returns the content `path` will have once the plan is executed, reading its current content
+/// the first time it is touched.
+fn touch<'a>(
+    targets: &'a mut IndexMap<PathBuf, Target>,
+    path: &Path,
+    layer: &Option<String>,
+) -> Result<&'a mut Content> {
+    if !targets.contains_key(path) {
+        let content = Content::read(path)?;
+        let target = Target {
+            layer: layer.clone(),
+            old: content.clone(),
+            new: content,
+        };
+        targets.insert(path.to_owned(), target);
+    }
+    Ok(&mut targets[path].new)
+}
+
+fn read_source(source: &Path) -> Result<Vec<u8>> {
+    std::fs::read(source).with_context(|| format!("failed to read `{}`", source.display()))
+}
+
+fn write_diff<W: Write>(out: &mut W, path: &Path, target: &Target, color: bool) -> Result<()> {
+    let paint = |code: &'static str| if color { code } else { "" };
+    let reset = paint(RESET);
+    let display = deploy::display_path(path);
+    let (old, new) = (&target.old, &target.new);
+
+    match &target.layer {
+        Some(layer) => writeln!(out, "{}diff {display} ({layer}){reset}", paint(BOLD))?,
+        None => writeln!(out, "{}diff {display}{reset}", paint(BOLD))?,
+    }
+    if old.link != new.link {
+        match (&old.link, &new.link) {
+            (_, Some(link)) => writeln!(out, "{}link => {}{reset}", paint(BOLD), link.display())?,
+            (Some(link), None) => writeln!(out, "{}unlink {}{reset}", paint(BOLD), link.display())?,
+            (None, None) => {}
+        }
+    }
+    if old.data == new.data {
+        return Ok(());
+    }
+
+    let old_name = if old.data.is_some() {
+        &display
+    } else {
+        "/dev/null"
+    };
+    let new_name = if new.data.is_some() {
+        &display
+    } else {
+        "/dev/null"
+    };
+    let (Ok(old), Ok(new)) = (
+        std::str::from_utf8(old.data.as_deref().unwrap_or_default()),
+        std::str::from_utf8(new.data.as_deref().unwrap_or_default()),
+    ) else {
+        writeln!(out, "Binary files {old_name} and {new_name} differ")?;
+        return Ok(());
+    };
+
+    writeln!(out, "{}--- {old_name}{reset}", paint(BOLD))?;
+    writeln!(out, "{}+++ {new_name}{reset}", paint(BOLD))?;
+    let diff = TextDiff::from_lines(old, new);
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        writeln!(out, "{}{}{reset}", paint(CYAN), hunk.header())?;
+        for change in hunk.iter_changes() {
+            let (sign, code) = match change.tag() {
+                ChangeTag::Delete => ("-", paint(RED)),
+                ChangeTag::Insert => ("+", paint(GREEN)),
+                ChangeTag::Equal => (" ", ""),
+            };
+            let end = if code.is_empty() { "" } else { reset };
+            let line = change.to_string_lossy();
+            writeln!(out, "{code}{sign}{}{end}", line.trim_end_matches('\n'))?;
+            if change.missing_newline() {
+                writeln!(out, "\\ No newline at end of file")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        plan::{Action, Step},
+        testing::TempDir,
+    };
+
+    fn write_block(path: &Path, name: &str, content: &str) -> Step {
+        let mut step = Step::new(Action::Update, name, path);
+        step.operations.push(Operation::WriteBlock {
+            path: path.to_owned(),
+            source: "/dots/block".into(),
+            name: name.into(),
+            comment: "#".into(),
+            hash: String::new(),
+            content: Some(content.into()),
+        });
+        step
+    }
+
+    #[test]
+    fn simulate_blocks_once_per_target() {
+        let root = TempDir::new("diff");
+        let path = root.join(".bashrc");
+        std::fs::write(&path, "export A=1\n").unwrap();
+
+        let plan = Plan {
+            steps: vec![write_block(&path, "a", "a"), write_block(&path, "b", "b")],
+        };
+        let targets = simulate(&plan).unwrap();
+        assert_eq!(targets.len(), 1);
+        let target = &targets[path.as_path()];
+        assert_eq!(target.old.data.as_deref(), Some(&b"export A=1\n"[..]));
+        assert_eq!(
+            String::from_utf8_lossy(target.new.data.as_deref().unwrap()),
+            "export A=1\n# >>> dfim:a >>>\na\n# <<< dfim:a <<<\n# >>> dfim:b >>>\nb\n# <<< dfim:b <<<\n"
+        );
+        assert_eq!(target.layer.as_deref(), Some("a"));
+    }
+}
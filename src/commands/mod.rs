@@ -1,12 +1,157 @@
+mod adopt;
+mod apply;
+mod clean;
+mod diff;
 mod lua;
+mod render;
+mod rollback;
+mod sources;
+mod status;
+mod uninstall;
 mod version;
+mod which;
 
-use crate::cli::{Cli, Commands};
+use std::{
+    collections::BTreeMap,
+    io::{stdout, Write},
+};
+
+use anyhow::Result;
+use mlua::Lua;
+
+use crate::{
+    cli::{Cli, Commands, OutputFormat, PlanArgs},
+    config::Config,
+    deploy::ManagedFile,
+    generation::Generation,
+    layer::Layer,
+    plan::Plan,
+    state::State,
+};
 
 pub fn exec(args: Cli) -> anyhow::Result<()> {
     match args.command {
+        Some(Commands::Adopt(ref adopt_args)) => adopt::exec(adopt_args, &args),
+        Some(Commands::Apply(ref apply_args)) => apply::exec(apply_args, &args),
+        Some(Commands::Clean(ref clean_args)) => clean::exec(clean_args, &args),
+        Some(Commands::Diff(ref diff_args)) => diff::exec(diff_args, &args),
         Some(Commands::Lua(args)) => lua::exec(args),
+        Some(Commands::Render(ref render_args)) => render::exec(render_args),
+        Some(Commands::Rollback(ref rollback_args)) => rollback::exec(rollback_args, &args),
+        Some(Commands::Sources(ref sources_args)) => sources::exec(sources_args, &args),
+        Some(Commands::Status(ref status_args)) => status::exec(status_args, &args),
+        Some(Commands::Uninstall(ref uninstall_args)) => uninstall::exec(uninstall_args, &args),
         Some(Commands::Version) => version::exec(&args),
+        Some(Commands::Which(ref which_args)) => which::exec(which_args, &args),
         _ => unimplemented!(),
     }
 }
+
+/// Creates a lua state and executes the configuration module.
+fn load_config() -> Result<Lua> {
+    let lua = crate::lua::create_state()?;
+    let config = Config::load(&lua)?;
+    crate::source::set_url_templates(config.url_templates)?;
+    crate::generation::set_keep(config.keep_generations)?;
+    Ok(lua)
+}
+
+/// Loads the configuration module and resolves the files managed by the named layers.
+///
+/// If `names` is empty, files for all layers are returned. Sources that are not fetched yet are
+/// only fetched if `fetch` is set, otherwise resolving their files fails.
+fn load_managed_files(
+    names: &[String],
+    fetch: bool,
+) -> Result<(Lua, Vec<Layer>, Vec<ManagedFile>)> {
+    let lua = load_config()?;
+    if fetch {
+        crate::lua::fetch_sources(&lua)?;
+    }
+    let (layers, files) = crate::lua::managed_files(&lua, names)?;
+    Ok((lua, layers, files))
+}
+
+/// Prints the plan if this is a dry run, otherwise executes it and reports each step.
+///
+/// If `command` is set, a [`Generation`] is recorded before executing so the plan can be rolled
+/// back later.
+fn run_plan(
+    plan: &Plan,
+    mut state: State,
+    command: Option<&str>,
+    args: &PlanArgs,
+    cli: &Cli,
+) -> Result<()> {
+    let mut out = stdout().lock();
+    if args.dry_run {
+        match args.format {
+            OutputFormat::Json => writeln!(out, "{}", serde_json::to_string_pretty(plan)?)?,
+            OutputFormat::Table if !cli.quiet => print_plan(&mut out, plan, cli.verbose > 0)?,
+            OutputFormat::Table => {}
+        }
+        return Ok(());
+    }
+
+    if let (Some(command), false) = (command, plan.is_empty()) {
+        Generation::create(command, plan, &state)?;
+    }
+
+    let report = args.format == OutputFormat::Table && !cli.quiet;
+    let result = plan.execute(&mut state, |step| {
+        if report {
+            // a closed output must not stop the plan halfway
+            let _ = writeln!(out, "{:<9} {}", step.action.past_tense(), step.describe());
+        }
+    });
+    // keep track of anything executed before a failure
+    if !plan.is_empty() {
+        state.save()?;
+    }
+    result?;
+    if let (Some(_), Some(keep)) = (command, crate::generation::keep()) {
+        Generation::prune(keep)?;
+    }
+
+    match args.format {
+        OutputFormat::Json => writeln!(out, "{}", serde_json::to_string_pretty(plan)?)?,
+        OutputFormat::Table if report => writeln!(out, "{}", summary(plan))?,
+        OutputFormat::Table => {}
+    }
+
+    Ok(())
+}
+
+fn print_plan(out: &mut impl Write, plan: &Plan, verbose: bool) -> Result<()> {
+    for step in &plan.steps {
+        let reason = step
+            .reason
+            .as_ref()
+            .map(|r| format!(" ({r})"))
+            .unwrap_or_default();
+        writeln!(out, "{:<9} {}{reason}", step.action, step.describe())?;
+        for op in &step.operations {
+            if verbose || !op.is_state_only() {
+                writeln!(out, "          {op}")?;
+            }
+        }
+    }
+    writeln!(out, "dry run: {}", summary(plan))?;
+    Ok(())
+}
+
+fn summary(plan: &Plan) -> String {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for step in &plan.steps {
+        *counts.entry(step.action.past_tense()).or_default() += 1;
+    }
+    if counts.is_empty() {
+        return "nothing to do".into();
+    }
+
+    counts
+        .into_iter()
+        .map(|(action, n)| format!("{n} {action}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,56 @@
+use std::io::{stdout, Write};
+
+use anyhow::Result;
+use mlua::{Table, Value};
+
+use crate::{
+    cli::RenderArgs,
+    deploy,
+    lua::{self, FactOverrides},
+};
+
+pub fn exec(args: &RenderArgs) -> Result<()> {
+    // facts must be replaced before the config runs, since it may branch on them
+    lua::set_fact_overrides(FactOverrides {
+        os: args.os.clone(),
+        arch: args.arch.clone(),
+        hostname: args.hostname.clone(),
+    })?;
+    let state = super::load_config()?;
+
+    let root: Table = state.globals().get(env!("CARGO_PKG_NAME"))?;
+    let module: Table = root.get("template")?;
+    let vars: Table = module.get("vars")?;
+    for (k, v) in &args.vars {
+        vars.set(k.as_str(), var_value(&state, v)?)?;
+    }
+
+    let path = std::path::absolute(&args.file)?;
+    // includes are limited to the source containing the file, if any
+    let mut root = path.parent().unwrap_or(&path).to_owned();
+    for (name, spec) in lua::get_sources(&state)? {
+        let dir = deploy::include_root(&name, &spec.source)?;
+        if path.starts_with(&dir) {
+            root = dir;
+            break;
+        }
+    }
+    let content = lua::render_file(&state, &path, &root)?;
+    stdout().lock().write_all(content.as_bytes())?;
+
+    Ok(())
+}
+
+/// Converts a `--set` value to a boolean or number if possible, otherwise a string.
+fn var_value<'lua>(lua: &'lua mlua::Lua, value: &str) -> Result<Value<'lua>> {
+    let value = match value {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        v => match (v.parse::<i64>(), v.parse::<f64>()) {
+            (Ok(i), _) => Value::Integer(i),
+            (_, Ok(f)) if f.is_finite() => Value::Number(f),
+            _ => Value::String(lua.create_string(v)?),
+        },
+    };
+    Ok(value)
+}
@@ -0,0 +1,76 @@
+use std::io::{stdout, Write};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    cli::{Cli, RollbackArgs},
+    generation::{self, Generation},
+    state::{timestamp, State},
+};
+
+pub fn exec(args: &RollbackArgs, cli: &Cli) -> Result<()> {
+    if args.list {
+        return list();
+    }
+    if args.prune {
+        return prune(cli);
+    }
+
+    let Some(generation) = Generation::latest()? else {
+        bail!("no generations to roll back");
+    };
+    let state = State::load()?;
+    let plan = generation.rollback(&state)?;
+
+    super::run_plan(&plan, state, None, &args.plan, cli)?;
+    if !args.plan.dry_run {
+        generation.remove()?;
+    }
+
+    Ok(())
+}
+
+fn prune(cli: &Cli) -> Result<()> {
+    // the limit is set by the configuration
+    let _lua = super::load_config()?;
+    let removed = Generation::prune(generation::keep().unwrap_or_default())?;
+    if cli.quiet {
+        return Ok(());
+    }
+
+    let mut out = stdout().lock();
+    if removed.is_empty() {
+        writeln!(out, "nothing to prune")?;
+    }
+    for g in &removed {
+        writeln!(out, "{:<9} generation {}", "removed", g.id)?;
+    }
+    Ok(())
+}
+
+fn list() -> Result<()> {
+    let generations = Generation::list()?;
+    let mut out = stdout().lock();
+    if generations.is_empty() {
+        writeln!(out, "no generations recorded")?;
+        return Ok(());
+    }
+
+    writeln!(
+        out,
+        "{:<10} {:<20} {:<9} PATHS",
+        "GENERATION", "CREATED", "COMMAND"
+    )?;
+    for g in generations.iter().rev() {
+        writeln!(
+            out,
+            "{:<10} {:<20} {:<9} {}",
+            g.id,
+            timestamp::format(g.created_at),
+            g.command,
+            g.paths.len()
+        )?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,240 @@
+use std::{
+    io::{stdout, Write},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::{
+    archive,
+    cli::{Cli, OutputFormat, SourcesArgs, SourcesCommand},
+    deploy::{self, display_path},
+    fetch, git,
+    lock::{self, LockFile},
+    lua::SourceMap,
+    source::{self, Source},
+};
+
+pub fn exec(args: &SourcesArgs, cli: &Cli) -> Result<()> {
+    let lua = super::load_config()?;
+    let sources = crate::lua::get_sources(&lua)?;
+
+    match &args.command {
+        SourcesCommand::List { format } => list(&sources, *format, cli),
+        SourcesCommand::Sync { names } => {
+            let disabled = crate::lua::get_disabled_sources(&lua)?;
+            sync(&sources, &disabled, names, cli)
+        }
+        SourcesCommand::Info { name } => info(&sources, name),
+    }
+}
+
+/// A registered source and where its files are on disk.
+#[derive(Debug, Serialize)]
+struct SourceInfo {
+    name: String,
+    kind: &'static str,
+    source: String,
+    path: PathBuf,
+    fetched: bool,
+}
+
+/// Returns every source sorted by name.
+fn sorted(sources: &SourceMap) -> Vec<(&String, &Source)> {
+    let mut sorted = sources
+        .iter()
+        .map(|(name, spec)| (name, &spec.source))
+        .collect::<Vec<_>>();
+    sorted.sort_by_key(|(name, _)| *name);
+    sorted
+}
+
+fn list(sources: &SourceMap, format: OutputFormat, cli: &Cli) -> Result<()> {
+    let mut infos = vec![];
+    for (name, source) in sorted(sources) {
+        let path = deploy::source_dir(name, source)?;
+        infos.push(SourceInfo {
+            name: name.clone(),
+            kind: source.kind(),
+            source: source.to_string(),
+            fetched: path.exists(),
+            path,
+        });
+    }
+
+    let mut out = stdout().lock();
+    match format {
+        OutputFormat::Json => writeln!(out, "{}", serde_json::to_string_pretty(&infos)?)?,
+        OutputFormat::Table if !cli.quiet => {
+            let width = infos
+                .iter()
+                .map(|s| s.name.len())
+                .chain(["NAME".len()])
+                .max()
+                .unwrap_or_default();
+            writeln!(out, "{:<width$} {:<7} PATH", "NAME", "KIND")?;
+            for s in &infos {
+                let missing = match (s.fetched, s.kind) {
+                    (true, _) => "",
+                    (false, "dir" | "file") => " (not found)",
+                    (false, _) => " (not fetched)",
+                };
+                writeln!(
+                    out,
+                    "{:<width$} {:<7} {}{missing}",
+                    s.name,
+                    s.kind,
+                    display_path(&s.path)
+                )?;
+            }
+        }
+        OutputFormat::Table => {}
+    }
+
+    Ok(())
+}
+
+fn sync(sources: &SourceMap, disabled: &[String], names: &[String], cli: &Cli) -> Result<()> {
+    for name in names {
+        if !sources.contains_key(name) {
+            bail!("source `{name}` does not exist");
+        }
+    }
+
+    let locked = lock::is_locked();
+    let mut out = stdout().lock();
+    let mut lockfile = LockFile::load()?;
+    for (name, source) in sorted(sources) {
+        if !names.is_empty() && !names.contains(name) {
+            continue;
+        }
+
+        let report = match source {
+            Source::Directory(_) | Source::File(_) => {
+                format!("{:<9} {name} (local source)", "skipped")
+            }
+            Source::Repo(repo) => {
+                let dir = deploy::source_dir(name, source)?;
+                let before = dir.is_dir().then(|| git::revision(&dir)).transpose()?;
+                let rev = if locked {
+                    let rev = lockfile.rev(name, repo)?.to_owned();
+                    fetch::sync(name, repo, &dir, Some(&rev))?
+                } else {
+                    fetch::sync(name, repo, &dir, None)?
+                };
+
+                let report = match before {
+                    None => format!("{:<9} {name} ({})", "cloned", fetch::short(&rev)),
+                    Some(b) if b == rev => format!("{:<9} {name}", "unchanged"),
+                    Some(b) => format!(
+                        "{:<9} {name} ({}..{})",
+                        "updated",
+                        fetch::short(&b),
+                        fetch::short(&rev)
+                    ),
+                };
+                lockfile.lock(name, &repo.url, Some(rev), None)?;
+                report
+            }
+            Source::Archive(value) => {
+                let dir = deploy::source_dir(name, source)?;
+                let before = dir
+                    .is_dir()
+                    .then(|| fetch::extracted_checksum(&dir))
+                    .flatten();
+                let checksum = fetch::extract(name, value, &dir)?;
+
+                let report = match before {
+                    None => format!("{:<9} {name} ({})", "extracted", fetch::short(&checksum)),
+                    Some(b) if b == checksum => format!("{:<9} {name}", "unchanged"),
+                    Some(b) => format!(
+                        "{:<9} {name} ({}..{})",
+                        "updated",
+                        fetch::short(&b),
+                        fetch::short(&checksum)
+                    ),
+                };
+                lockfile.lock(name, value, None, Some(checksum))?;
+                report
+            }
+        };
+        if !cli.quiet {
+            writeln!(out, "{report}")?;
+        }
+    }
+
+    if !locked {
+        if names.is_empty() {
+            let synced = sources
+                .iter()
+                .filter(|(_, s)| matches!(s.source, Source::Repo(_) | Source::Archive(_)))
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>();
+            lockfile.prune(&synced, disabled);
+        }
+        lockfile.save()?;
+    }
+
+    Ok(())
+}
+
+fn info(sources: &SourceMap, name: &str) -> Result<()> {
+    let Some(spec) = sources.get(name) else {
+        bail!("source `{name}` does not exist");
+    };
+    let source = &spec.source;
+    let dir = deploy::source_dir(name, source)?;
+
+    let mut out = stdout().lock();
+    writeln!(out, "name:     {name}")?;
+    writeln!(out, "kind:     {}", source.kind())?;
+    writeln!(out, "source:   {source}")?;
+    if let Source::Repo(r) = source {
+        writeln!(out, "url:      {}", source::repo_url(&r.url)?)?;
+        if let Some(reference) = &r.reference {
+            writeln!(out, "ref:      {} {}", reference.key(), reference.value())?;
+        }
+    }
+    if let Source::Archive(a) = source {
+        writeln!(out, "archive:  {}", archive::resolve_path(a)?.display())?;
+    }
+    if let Some(locked) = LockFile::load()?.sources.get(name) {
+        if let Some(rev) = &locked.rev {
+            writeln!(out, "locked:   {rev}")?;
+        }
+        if let Some(sha256) = &locked.sha256 {
+            writeln!(out, "locked:   sha256 {sha256}")?;
+        }
+    }
+    writeln!(out, "path:     {}", display_path(&dir))?;
+    if let Some(subdir) = &spec.subdir {
+        writeln!(out, "subdir:   {}", subdir.display())?;
+    }
+    if !spec.ignore.is_empty() {
+        writeln!(out, "ignore:   {}", spec.ignore.join(" "))?;
+    }
+    if spec.priority != 0 {
+        writeln!(out, "priority: {}", spec.priority)?;
+    }
+    if !dir.exists() {
+        writeln!(out, "fetched:  no")?;
+        return Ok(());
+    }
+    if let Some(checksum) = fetch::extracted_checksum(&dir) {
+        writeln!(out, "sha256:   {checksum}")?;
+    }
+    if dir.join(".git").exists() {
+        writeln!(out, "revision: {}", git::revision(&dir)?)?;
+        let branch = git::branch(&dir)?;
+        writeln!(
+            out,
+            "branch:   {}",
+            branch.as_deref().unwrap_or("(detached)")
+        )?;
+        let dirty = if git::is_dirty(&dir)? { "yes" } else { "no" };
+        writeln!(out, "dirty:    {dirty}")?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use std::io::{stdout, Write};
+
+use anyhow::{bail, Result};
+
+use crate::{
+    cli::{Cli, OutputFormat, StatusArgs},
+    deploy,
+    state::State,
+    status::{self, Status},
+};
+
+pub fn exec(args: &StatusArgs, cli: &Cli) -> Result<()> {
+    let (_lua, _, files) = super::load_managed_files(&[], false)?;
+    let state = State::load()?;
+    let statuses = status::check_all(&files, &state)?;
+    let mut out = stdout().lock();
+
+    match args.format {
+        OutputFormat::Json => writeln!(out, "{}", serde_json::to_string_pretty(&statuses)?)?,
+        OutputFormat::Table if !cli.quiet => {
+            let width = statuses
+                .iter()
+                .map(|s| s.layer.len())
+                .chain(["LAYER".len()])
+                .max()
+                .unwrap_or_default();
+            writeln!(out, "{:<14} {:<width$} TARGET", "STATUS", "LAYER")?;
+            for s in &statuses {
+                writeln!(
+                    out,
+                    "{:<14} {:<width$} {}",
+                    s.status,
+                    s.layer,
+                    deploy::display_path(&s.target)
+                )?;
+            }
+        }
+        OutputFormat::Table => {}
+    }
+
+    let drifted = statuses
+        .iter()
+        .filter(|s| s.status != Status::UpToDate)
+        .count();
+    if args.exit_code && drifted > 0 {
+        bail!("{drifted} target(s) are not up to date");
+    }
+
+    Ok(())
+}
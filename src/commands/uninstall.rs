@@ -0,0 +1,16 @@
+use anyhow::Result;
+
+use crate::{
+    cli::{Cli, UninstallArgs},
+    plan,
+    state::State,
+};
+
+pub fn exec(args: &UninstallArgs, cli: &Cli) -> Result<()> {
+    let state = State::load()?;
+    let plan = plan::remove(&state, |_, entry| {
+        args.layers.is_empty() || args.layers.contains(&entry.layer)
+    })?;
+
+    super::run_plan(&plan, state, Some("uninstall"), &args.plan, cli)
+}
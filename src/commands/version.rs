@@ -1,8 +1,10 @@
+use std::io::{stdout, Write};
+
 use anyhow::Result;
 
 use crate::cli::Cli;
 
 pub fn exec(args: &Cli) -> Result<()> {
-    println!("{}", args.version_string());
+    writeln!(stdout().lock(), "{}", args.version_string())?;
     Ok(())
 }
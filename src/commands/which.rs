@@ -0,0 +1,109 @@
+use std::{
+    io::{stdout, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::{
+    cli::{Cli, OutputFormat, WhichArgs},
+    config::home_dir,
+    deploy::{self, display_path},
+};
+
+/// A layer that provides the target and whether it is deployed.
+#[derive(Debug, Serialize)]
+struct Provider {
+    status: &'static str,
+    layer: String,
+    source: String,
+    priority: i64,
+    path: PathBuf,
+}
+
+pub fn exec(args: &WhichArgs, cli: &Cli) -> Result<()> {
+    let lua = super::load_config()?;
+    let sources = crate::lua::get_sources(&lua)?;
+    let layers = crate::lua::get_layers(&lua)?;
+    let mut candidates = deploy::candidates(&layers, &sources, home_dir())?;
+
+    let Some((target, files)) = resolve_target(&args.target)?
+        .into_iter()
+        .find_map(|t| candidates.swap_remove_entry(&t))
+    else {
+        bail!(
+            "target `{}` is not managed by any layer",
+            args.target.display()
+        );
+    };
+
+    let deployed = deploy::deployed(&target, &files);
+    let providers = files
+        .iter()
+        .map(|f| Provider {
+            status: match &deployed {
+                Ok(d) if d.contains(&f) => "deployed",
+                Ok(_) => "shadowed",
+                Err(_) if f.priority == files[0].priority => "conflict",
+                Err(_) => "shadowed",
+            },
+            layer: f.layer.clone(),
+            source: f.source.clone(),
+            priority: f.priority,
+            path: f.source_path.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    let mut out = stdout().lock();
+    match args.format {
+        OutputFormat::Json => writeln!(out, "{}", serde_json::to_string_pretty(&providers)?)?,
+        OutputFormat::Table if !cli.quiet => {
+            let layer_width = providers
+                .iter()
+                .map(|p| p.layer.len())
+                .chain(["LAYER".len()])
+                .max()
+                .unwrap_or_default();
+            let source_width = providers
+                .iter()
+                .map(|p| p.source.len())
+                .chain(["SOURCE".len()])
+                .max()
+                .unwrap_or_default();
+            writeln!(out, "{}", display_path(&target))?;
+            writeln!(
+                out,
+                "{:<9} {:<layer_width$} {:<source_width$} {:>8} PATH",
+                "STATUS", "LAYER", "SOURCE", "PRIORITY"
+            )?;
+            for p in &providers {
+                writeln!(
+                    out,
+                    "{:<9} {:<layer_width$} {:<source_width$} {:>8} {}",
+                    p.status,
+                    p.layer,
+                    p.source,
+                    p.priority,
+                    display_path(&p.path)
+                )?;
+            }
+        }
+        OutputFormat::Table => {}
+    }
+
+    deployed.map(|_| ())
+}
+
+/// Returns the absolute paths `target` might refer to, which is relative to the working directory
+/// and then the home directory.
+fn resolve_target(target: &Path) -> Result<Vec<PathBuf>> {
+    if target.is_absolute() {
+        return Ok(vec![target.to_owned()]);
+    }
+    if let Ok(rest) = target.strip_prefix("~") {
+        return Ok(vec![home_dir().join(rest)]);
+    }
+
+    Ok(vec![std::path::absolute(target)?, home_dir().join(target)])
+}
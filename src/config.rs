@@ -1,23 +1,46 @@
 use std::{
+    collections::HashMap,
     path::{Path, PathBuf},
     sync::OnceLock,
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use log::debug;
-use mlua::Lua;
+use mlua::{Lua, LuaSerdeExt, Table, Value};
+use serde::Deserialize;
 
-use crate::path;
+use crate::{layer::ConflictPolicy, path};
 
 static OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
 
-#[derive(Debug, Default)]
+/// Number of generations kept when `dfim.options` does not set `keep_generations`.
+const DEFAULT_KEEP_GENERATIONS: usize = 20;
+
+/// Global options set by the configuration module through the `dfim.options` table.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
 pub struct Config {
-    // TODO: add config fields
+    /// Conflict policy for layers that do not set their own.
+    pub on_conflict: ConflictPolicy,
+    /// Repo shorthand prefixes and the URL templates they expand to.
+    pub url_templates: HashMap<String, String>,
+    /// Number of generations kept for `dfim rollback`, or 0 to keep every generation.
+    pub keep_generations: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            on_conflict: ConflictPolicy::default(),
+            url_templates: HashMap::new(),
+            keep_generations: DEFAULT_KEEP_GENERATIONS,
+        }
+    }
 }
 
 impl Config {
-    pub fn load(lua: &Lua) -> Result<()> {
+    /// Executes the configuration module and reads the resulting options.
+    pub fn load(lua: &Lua) -> Result<Self> {
         if let Some(f) = Self::get_module_file() {
             debug!("Loading config module: {}", f.display());
             let name = f.to_string_lossy().to_string();
@@ -26,7 +49,18 @@ impl Config {
             debug!("No config module found");
         }
 
-        Ok(())
+        Self::from_lua(lua)
+    }
+
+    /// Reads options from the `dfim.options` table without executing the configuration module.
+    pub fn from_lua(lua: &Lua) -> Result<Self> {
+        let root: Table = lua.globals().get(env!("CARGO_PKG_NAME"))?;
+        match root.get::<_, Value>("options")? {
+            Value::Nil => Ok(Self::default()),
+            value => lua
+                .from_value(value)
+                .context("invalid value in `dfim.options`"),
+        }
     }
 
     pub fn set_override(path: &Path) -> Result<()> {
@@ -63,6 +97,85 @@ impl Config {
     }
 }
 
+/// Returns the directory relative paths in the configuration are resolved against, which is the
+/// directory of the configuration module or the working directory if there is none.
+pub fn base_dir() -> Result<PathBuf> {
+    match Config::get_module_file().as_deref().and_then(Path::parent) {
+        Some(dir) if !dir.as_os_str().is_empty() => Ok(std::path::absolute(dir)?),
+        _ => Ok(std::env::current_dir()?),
+    }
+}
+
+/// Expands a leading `~` and any `$VAR` or `${VAR}` environment variables in `value`, then resolves
+/// a relative result against [`base_dir`].
+pub fn expand_path(value: &str) -> Result<PathBuf> {
+    let value = expand_vars(value)?;
+    let path = Path::new(&value);
+    let path = match path.strip_prefix("~") {
+        Ok(rest) => home_dir().join(rest),
+        Err(_) if path.is_relative() => base_dir()?.join(path),
+        Err(_) => path.to_owned(),
+    };
+    // drops `.` components, e.g. for `{ dir = '.' }`
+    Ok(path.components().collect())
+}
+
+/// Returns `path` as `./path` if it is inside of [`base_dir`] or `~/path` if it is inside of the
+/// home directory, so the value does not depend on where the configuration is checked out.
+pub fn portable_path(path: &Path) -> Result<String> {
+    Ok(portable_path_in(path, &base_dir()?, home_dir()))
+}
+
+fn portable_path_in(path: &Path, base: &Path, home: &Path) -> String {
+    let join = |prefix: &str, rest: &Path| {
+        rest.components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .fold(prefix.to_owned(), |acc, c| format!("{acc}/{c}"))
+    };
+    if let Ok(rest) = path.strip_prefix(base) {
+        join(".", rest)
+    } else if let Ok(rest) = path.strip_prefix(home) {
+        join("~", rest)
+    } else {
+        path.to_string_lossy().into()
+    }
+}
+
+fn expand_vars(value: &str) -> Result<String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(i) = rest.find('$') {
+        result.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        let (name, next) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => bail!("unterminated `${{` in `{value}`"),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+
+        if name.is_empty() {
+            result.push('$');
+        } else {
+            let var = std::env::var(name).with_context(|| {
+                format!("environment variable `{name}` in `{value}` is not set")
+            })?;
+            result.push_str(&var);
+        }
+        rest = next;
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
+
 pub fn home_dir() -> &'static Path {
     static HOME_DIR: OnceLock<PathBuf> = OnceLock::new();
     HOME_DIR.get_or_init(|| home::home_dir().unwrap())
@@ -82,7 +195,7 @@ pub fn data_dir() -> &'static Path {
     static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
     DATA_DIR.get_or_init(|| {
         if let Some(d) = std::env::var_os("XDG_DATA_HOME") {
-            return PathBuf::from(d);
+            return PathBuf::from(d).join(env!("CARGO_PKG_NAME"));
         }
         if cfg!(windows) {
             config_dir().join("data")
@@ -96,3 +209,48 @@ pub fn plugin_dir() -> &'static Path {
     static PLUGIN_DIR: OnceLock<PathBuf> = OnceLock::new();
     PLUGIN_DIR.get_or_init(|| data_dir().join("plugins"))
 }
+
+pub fn sources_dir() -> &'static Path {
+    static SOURCES_DIR: OnceLock<PathBuf> = OnceLock::new();
+    SOURCES_DIR.get_or_init(|| data_dir().join("sources"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_env_vars() {
+        std::env::set_var("DFIM_TEST_DOTS", "dots");
+        assert_eq!(
+            expand_vars("/srv/$DFIM_TEST_DOTS/a").unwrap(),
+            "/srv/dots/a"
+        );
+        assert_eq!(
+            expand_vars("/srv/${DFIM_TEST_DOTS}_a").unwrap(),
+            "/srv/dots_a"
+        );
+        assert_eq!(expand_vars("/srv/$/a").unwrap(), "/srv/$/a");
+        assert!(expand_vars("/srv/$DFIM_TEST_UNSET_VAR").is_err());
+        assert!(expand_vars("/srv/${DFIM_TEST_DOTS").is_err());
+    }
+
+    #[test]
+    fn portable_paths() {
+        let (base, home) = (Path::new("/home/a/dots"), Path::new("/home/a"));
+        let path = |p: &str| portable_path_in(Path::new(p), base, home);
+        assert_eq!(path("/home/a/dots/vendor/x"), "./vendor/x");
+        assert_eq!(path("/home/a/dots"), ".");
+        assert_eq!(path("/home/a/src/x"), "~/src/x");
+        assert_eq!(path("/srv/git/x"), "/srv/git/x");
+    }
+
+    #[test]
+    fn expand_home() {
+        assert_eq!(expand_path("~/dots").unwrap(), home_dir().join("dots"));
+        assert_eq!(
+            expand_path("/srv/dots").unwrap(),
+            PathBuf::from("/srv/dots")
+        );
+    }
+}
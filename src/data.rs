@@ -0,0 +1,73 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JValue;
+
+use crate::{config::data_dir, fs};
+
+static ANSWERS: OnceLock<BTreeMap<String, JValue>> = OnceLock::new();
+
+/// Answers prompts with the values in a JSON object file instead of asking interactively.
+pub fn set_answers_file(path: &Path) -> Result<()> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read answers file `{}`", path.display()))?;
+    let answers = serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse answers file `{}`", path.display()))?;
+    if ANSWERS.set(answers).is_err() {
+        anyhow::bail!("failed to set answers file");
+    }
+    Ok(())
+}
+
+/// Returns `true` if prompts are answered from a file.
+pub fn has_answers() -> bool {
+    ANSWERS.get().is_some()
+}
+
+/// Returns the answer for the prompt `name` from the answers file, if any.
+pub fn answer(name: &str) -> Option<&'static JValue> {
+    ANSWERS.get().and_then(|a| a.get(name))
+}
+
+/// Persistent per-machine values set through `dfim.data` and `dfim.prompt`, stored in
+/// [`data_dir`].
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DataStore {
+    pub values: BTreeMap<String, JValue>,
+}
+
+impl DataStore {
+    /// Returns the path of the data file.
+    pub fn path() -> PathBuf {
+        data_dir().join("data.json")
+    }
+
+    /// Loads the data file, or returns an empty store if it does not exist.
+    pub fn load() -> Result<Self> {
+        let path = Self::path();
+        if !path.is_file() {
+            debug!("No data file found at `{}`", path.display());
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read data file `{}`", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("failed to parse data file `{}`", path.display()))
+    }
+
+    /// Writes the data file.
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        debug!("Saving data to `{}`", path.display());
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write_atomic(&path, content.as_bytes())
+    }
+}
@@ -0,0 +1,592 @@
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use indexmap::IndexMap;
+use log::debug;
+
+use crate::{
+    block,
+    config::{home_dir, sources_dir},
+    fetch,
+    ignore::IgnoreRules,
+    layer::{ConflictPolicy, DeployMode, Layer, LinkStyle},
+    lua::SourceMap,
+    source::Source,
+    template,
+};
+
+/// A single file managed by a layer, resolved to absolute paths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManagedFile {
+    pub layer: String,
+    pub source: String,
+    /// Absolute path of the file inside of the source.
+    pub source_path: PathBuf,
+    /// Absolute path where the file is deployed.
+    pub target: PathBuf,
+    pub mode: DeployMode,
+    pub link: LinkStyle,
+    /// Comment prefix for block markers.
+    pub comment: Option<String>,
+    pub on_conflict: Option<ConflictPolicy>,
+    /// Priority of the layer, or of its source if the layer does not set one.
+    pub priority: i64,
+    /// Rendered content for templates, which are never deployed as links.
+    pub content: Option<Vec<u8>>,
+}
+
+/// Every file that provides a target, keyed by target in the order they were first seen.
+///
+/// Files for each target are ordered from highest to lowest priority.
+pub type Candidates = IndexMap<PathBuf, Vec<ManagedFile>>;
+
+/// Returns the absolute local directory for a source, without fetching it.
+///
+/// For file sources, this is the path of the file itself.
+pub fn source_dir(name: &str, source: &Source) -> Result<PathBuf> {
+    let dir = match source {
+        Source::Directory(d) | Source::File(d) => d.to_owned(),
+        Source::Repo(_) | Source::Archive(_) => sources_dir().join(name),
+    };
+
+    // link targets must not depend on the working directory
+    Ok(std::path::absolute(dir)?)
+}
+
+/// Returns the directory that templates from a source can include files from, which is the
+/// source directory, or the directory containing a file source.
+pub fn include_root(name: &str, source: &Source) -> Result<PathBuf> {
+    let dir = source_dir(name, source)?;
+    match (source, dir.parent()) {
+        (Source::File(_), Some(parent)) => Ok(parent.to_owned()),
+        _ => Ok(dir),
+    }
+}
+
+/// Clones a repo source or extracts an archive source into [`sources_dir`], if needed.
+///
+/// Repo sources are cloned the first time they are used, and archive sources are extracted
+/// whenever the archive changes. Local sources are left as is.
+pub fn fetch_source(name: &str, source: &Source) -> Result<()> {
+    let dir = source_dir(name, source)?;
+    match source {
+        Source::Repo(r) => fetch::ensure(name, r, &dir),
+        Source::Archive(a) => fetch::extract(name, a, &dir).map(|_| ()),
+        Source::Directory(_) | Source::File(_) => Ok(()),
+    }
+}
+
+/// Returns the local directory containing the files for a source.
+///
+/// This never fetches the source, so repo and archive sources must have been fetched with
+/// [`fetch_source`] first.
+pub fn source_root(name: &str, source: &Source) -> Result<PathBuf> {
+    let root = source_dir(name, source)?;
+    match source {
+        Source::Repo(_) | Source::Archive(_) if !root.exists() => {
+            bail!("source `{name}` is not fetched, run `dfim sources sync` or `dfim apply` first")
+        }
+        Source::File(_) => {
+            if !root.is_file() {
+                bail!("source `{name}` file `{}` not found", root.display());
+            }
+            return Ok(root);
+        }
+        _ => {}
+    }
+    if !root.is_dir() {
+        bail!("source `{name}` directory `{}` not found", root.display());
+    }
+
+    Ok(root)
+}
+
+/// Resolves every file managed by `layers` into a list of [`ManagedFile`].
+///
+/// Targets are resolved relative to `root`, which is typically [`home_dir`]. When more than one
+/// layer provides the same target, the one with the highest priority is deployed and the others
+/// are shadowed, see [`deployed`].
+pub fn collect(layers: &[Layer], sources: &SourceMap, root: &Path) -> Result<Vec<ManagedFile>> {
+    let mut files = vec![];
+    for (target, files_for_target) in candidates(layers, sources, root)? {
+        let deployed = deployed(&target, &files_for_target)?;
+        for other in files_for_target.iter().filter(|f| !deployed.contains(f)) {
+            debug!(
+                "Target `{}` from layer `{}` is shadowed by layer `{}`",
+                target.display(),
+                other.layer,
+                deployed[0].layer
+            );
+        }
+        files.extend(deployed.into_iter().cloned());
+    }
+
+    Ok(files)
+}
+
+/// Returns the files that are deployed for `target`.
+///
+/// Blocks are deployed side by side, one for each layer, so every file is deployed if they are
+/// all blocks. Otherwise, this is the [`winner`] of `files`.
+pub fn deployed<'a>(target: &Path, files: &'a [ManagedFile]) -> Result<Vec<&'a ManagedFile>> {
+    let (blocks, others) = files
+        .iter()
+        .partition::<Vec<_>, _>(|f| f.mode == DeployMode::Block);
+    match (blocks.as_slice(), others.as_slice()) {
+        ([], _) => Ok(vec![winner(target, files)?]),
+        ([block, ..], [other, ..]) => bail!(
+            "target `{}` is managed as a block by layer `{}` and as a file by layer `{}`",
+            target.display(),
+            block.layer,
+            other.layer
+        ),
+        _ => {
+            for (i, file) in blocks.iter().enumerate() {
+                if blocks[..i].iter().any(|f| f.layer == file.layer) {
+                    bail!(
+                        "target `{}` has more than one block from layer `{}`",
+                        target.display(),
+                        file.layer
+                    );
+                }
+            }
+            Ok(blocks)
+        }
+    }
+}
+
+/// Returns the file that is deployed for `target`, which is the first of `files` as long as no
+/// other file has the same priority.
+pub fn winner<'a>(target: &Path, files: &'a [ManagedFile]) -> Result<&'a ManagedFile> {
+    match files {
+        [] => bail!("target `{}` is not managed by any layer", target.display()),
+        [first, second, ..] if first.priority == second.priority => bail!(
+            "target `{}` is managed by both layer `{}` and `{}` with priority {} (set `priority` on a layer or source to choose one)",
+            target.display(),
+            first.layer,
+            second.layer,
+            first.priority
+        ),
+        [first, ..] => Ok(first),
+    }
+}
+
+/// Resolves every file provided by `layers`, including files shadowed by a higher priority
+/// layer.
+pub fn candidates(layers: &[Layer], sources: &SourceMap, root: &Path) -> Result<Candidates> {
+    let mut candidates = Candidates::new();
+
+    for layer in layers {
+        let spec = sources
+            .get(&layer.source)
+            .with_context(|| format!("layer `{}` uses unknown source", layer.name))?;
+        let source = &spec.source;
+        // ignore rules are relative to the source root, even when a subdir is deployed
+        let ignore_root = source_root(&layer.source, source)?;
+        let mut src_root = ignore_root.clone();
+        if let Some(subdir) = &spec.subdir {
+            src_root = src_root.join(subdir);
+            if !src_root.is_dir() {
+                bail!(
+                    "source `{}` subdir `{}` not found",
+                    layer.source,
+                    subdir.display()
+                );
+            }
+        }
+        let ignore = match source {
+            Source::File(_) => IgnoreRules::default(),
+            _ => IgnoreRules::load(&ignore_root, &spec.ignore)?,
+        };
+        debug!(
+            "Collecting files for layer `{}` from `{}`",
+            layer.name,
+            src_root.display()
+        );
+
+        let mut mappings = vec![];
+        if let Source::File(_) = source {
+            // a single file is deployed to its own name unless the layer maps it elsewhere
+            if template::is_partial(&src_root) {
+                bail!(
+                    "file source `{}` is a partial and cannot be deployed",
+                    layer.source
+                );
+            }
+            let target = match layer.files.as_slice() {
+                [] => {
+                    template::strip_extension(Path::new(src_root.file_name().unwrap_or_default()))
+                }
+                [f] => f.target.clone(),
+                _ => bail!(
+                    "layer `{}` maps more than one file from file source `{}`",
+                    layer.name,
+                    layer.source
+                ),
+            };
+            mappings.push((src_root.clone(), target));
+        } else if layer.files.is_empty() {
+            for p in walk_files(&ignore_root, &src_root, &ignore)? {
+                let rel = template::strip_extension(p.strip_prefix(&src_root)?);
+                mappings.push((p, rel));
+            }
+        } else {
+            for f in &layer.files {
+                let p = src_root.join(&f.source);
+                if ignore.is_ignored(p.strip_prefix(&ignore_root)?, p.is_dir()) {
+                    bail!(
+                        "layer `{}` file `{}` is ignored by source `{}`",
+                        layer.name,
+                        f.source.display(),
+                        layer.source
+                    );
+                }
+                if p.is_dir() {
+                    for child in walk_files(&ignore_root, &p, &ignore)? {
+                        let rel = f
+                            .target
+                            .join(template::strip_extension(child.strip_prefix(&p)?));
+                        mappings.push((child, rel));
+                    }
+                } else if template::is_partial(&p) {
+                    bail!(
+                        "layer `{}` file `{}` is a partial and cannot be deployed",
+                        layer.name,
+                        f.source.display()
+                    );
+                } else if p.is_file() {
+                    mappings.push((p, f.target.clone()));
+                } else {
+                    bail!(
+                        "layer `{}` file `{}` not found in source `{}`",
+                        layer.name,
+                        f.source.display(),
+                        layer.source
+                    );
+                }
+            }
+        }
+
+        let priority = layer.priority.unwrap_or(spec.priority);
+        for (source_path, rel) in mappings {
+            let target = root.join(rel);
+            // a link would point at the unrendered template
+            let mode = if template::is_template(&source_path) && layer.mode == DeployMode::Link {
+                DeployMode::Copy
+            } else {
+                layer.mode
+            };
+            candidates
+                .entry(target.clone())
+                .or_default()
+                .push(ManagedFile {
+                    layer: layer.name.clone(),
+                    source: layer.source.clone(),
+                    source_path,
+                    target,
+                    mode,
+                    link: layer.link,
+                    comment: layer.comment.clone(),
+                    on_conflict: layer.on_conflict,
+                    priority,
+                    content: None,
+                });
+        }
+    }
+
+    // stable sort keeps layer order between equal priorities
+    for files in candidates.values_mut() {
+        files.sort_by_key(|f| std::cmp::Reverse(f.priority));
+    }
+    Ok(candidates)
+}
+
+/// Recursively lists files in `dir`, skipping version control metadata, template partials, and
+/// paths ignored relative to the source `root`.
+fn walk_files(root: &Path, dir: &Path, ignore: &IgnoreRules) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut stack = vec![dir.to_owned()];
+
+    while let Some(d) = stack.pop() {
+        let mut entries = std::fs::read_dir(&d)
+            .with_context(|| format!("failed to read directory `{}`", d.display()))?
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let is_dir = path.is_dir();
+            if entry.file_name() == ".git"
+                || ignore.is_ignored(path.strip_prefix(root)?, is_dir)
+                || (!is_dir && template::is_partial(&path))
+            {
+                continue;
+            }
+            if is_dir {
+                stack.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// Returns the content `file` should have once deployed.
+pub fn desired_content(file: &ManagedFile) -> Result<Vec<u8>> {
+    if let Some(content) = &file.content {
+        return Ok(content.clone());
+    }
+    std::fs::read(&file.source_path)
+        .with_context(|| format!("failed to read `{}`", file.source_path.display()))
+}
+
+/// Returns the body of the block `file` should have in its target.
+pub fn desired_block(file: &ManagedFile) -> Result<String> {
+    let content = String::from_utf8(desired_content(file)?).with_context(|| {
+        format!(
+            "cannot deploy `{}` as a block, file is not valid UTF-8",
+            file.source_path.display()
+        )
+    })?;
+    Ok(block::body(&content))
+}
+
+/// Returns the body of the block `name` in `target`, or `None` if the file or the block does not
+/// exist.
+pub fn deployed_block(target: &Path, name: &str) -> Result<Option<String>> {
+    let text = match std::fs::read_to_string(target) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read `{}`", target.display())),
+    };
+    let body = block::find(&text, name)
+        .with_context(|| format!("failed to find block in `{}`", target.display()))?;
+    Ok(body.map(ToOwned::to_owned))
+}
+
+/// Returns the path a symlink for `file` should contain, based on the link style.
+pub fn link_value(file: &ManagedFile) -> PathBuf {
+    match (file.link, file.target.parent()) {
+        (LinkStyle::Relative, Some(parent)) => relative_path(parent, &file.source_path),
+        _ => file.source_path.clone(),
+    }
+}
+
+/// Lexically computes the path to `to` relative to the directory `from`.
+///
+/// Both paths are expected to be absolute. Symlinks are not resolved, so that links stay valid
+/// when a parent directory is moved or mounted elsewhere.
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from = from.components().collect::<Vec<_>>();
+    let to = to.components().collect::<Vec<_>>();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for c in &to[common..] {
+        path.push(c);
+    }
+    path
+}
+
+/// Returns `true` if `link` is a symlink that resolves to `path`, without following any other
+/// links in between.
+pub fn links_to(link: &Path, path: &Path) -> Result<bool> {
+    let value = std::fs::read_link(link)?;
+    let resolved = match link.parent() {
+        Some(parent) if value.is_relative() => parent.join(value),
+        _ => value,
+    };
+    Ok(normalize(&resolved) == normalize(path))
+}
+
+/// Lexically removes `.` and `..` components from a path.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            other => result.push(other),
+        }
+    }
+    result
+}
+
+#[cfg(unix)]
+pub fn symlink(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+pub fn symlink(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+/// Formats a path for display, replacing the home directory prefix with `~`.
+pub fn display_path(path: &Path) -> String {
+    match path.strip_prefix(home_dir()) {
+        Ok(p) => format!("~{}{}", crate::pathsep!(), p.display()),
+        Err(_) => path.display().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ignore::IGNORE_FILE, layer::FileMapping, source::SourceSpec, testing::TempDir};
+
+    #[test]
+    fn relative_path_sibling() {
+        let p = relative_path(Path::new("/home/a/.config"), Path::new("/home/a/dots/foo"));
+        assert_eq!(p, PathBuf::from("../dots/foo"));
+    }
+
+    #[test]
+    fn relative_path_nested() {
+        let p = relative_path(Path::new("/home/a"), Path::new("/home/a/dots/foo"));
+        assert_eq!(p, PathBuf::from("dots/foo"));
+    }
+
+    #[test]
+    fn normalize_parent() {
+        let p = normalize(Path::new("/home/a/.config/../dots/./foo"));
+        assert_eq!(p, PathBuf::from("/home/a/dots/foo"));
+    }
+
+    fn dir_layer() -> Layer {
+        Layer {
+            name: "dots".into(),
+            source: "dots".into(),
+            files: vec![],
+            mode: DeployMode::Link,
+            link: LinkStyle::Absolute,
+            comment: None,
+            on_conflict: None,
+            priority: None,
+            hook: vec![],
+        }
+    }
+
+    #[test]
+    fn candidates_subdir_ignore_file() {
+        let root = TempDir::new("deploy");
+        std::fs::create_dir_all(root.join("home")).unwrap();
+        std::fs::write(root.join(IGNORE_FILE), "home/README.md\n*.bak\n").unwrap();
+        for f in [
+            "README.md",
+            "home/.bashrc",
+            "home/README.md",
+            "home/.bashrc.bak",
+        ] {
+            std::fs::write(root.join(f), "").unwrap();
+        }
+
+        let sources = SourceMap::from([(
+            "dots".to_owned(),
+            SourceSpec {
+                subdir: Some("home".into()),
+                ..SourceSpec::from(Source::Directory(root.to_path_buf()))
+            },
+        )]);
+        let mut layer = dir_layer();
+        let target = Path::new("/home/a");
+        let found = candidates(&[layer.clone()], &sources, target);
+        layer.files = vec![FileMapping::same("README.md")];
+        let mapped = candidates(&[layer], &sources, target);
+
+        let found = found.unwrap().into_keys().collect::<Vec<_>>();
+        assert_eq!(found, vec![target.join(".bashrc")]);
+        assert!(mapped.is_err());
+    }
+
+    #[test]
+    fn candidates_skip_partials() {
+        let root = TempDir::new("partials");
+        std::fs::create_dir_all(root.join("partials")).unwrap();
+        for f in [".gitconfig.tmpl", "partials/_user.tmpl", "_vimrc"] {
+            std::fs::write(root.join(f), "").unwrap();
+        }
+
+        let sources = SourceMap::from([(
+            "dots".to_owned(),
+            SourceSpec::from(Source::Directory(root.to_path_buf())),
+        )]);
+        let mut layer = dir_layer();
+        let target = Path::new("/home/a");
+        let found = candidates(&[layer.clone()], &sources, target);
+        layer.files = vec![FileMapping::same("partials/_user.tmpl")];
+        let mapped = candidates(&[layer], &sources, target);
+
+        let found = found.unwrap().into_keys().collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![target.join(".gitconfig"), target.join("_vimrc")]
+        );
+        assert!(mapped.is_err());
+    }
+
+    fn file(layer: &str, priority: i64) -> ManagedFile {
+        ManagedFile {
+            layer: layer.into(),
+            source: layer.into(),
+            source_path: format!("/dots/{layer}/a").into(),
+            target: "/home/a/a".into(),
+            mode: Default::default(),
+            link: Default::default(),
+            comment: None,
+            on_conflict: None,
+            priority,
+            content: None,
+        }
+    }
+
+    #[test]
+    fn source_root_not_fetched() {
+        let name = format!("dfim-unfetched-{}", std::process::id());
+        let source = Source::Repo("https://example.invalid/dots.git".into());
+        let err = source_root(&name, &source).unwrap_err();
+        assert!(err.to_string().contains("is not fetched"));
+        assert!(!source_dir(&name, &source).unwrap().exists());
+    }
+
+    #[test]
+    fn winner_by_priority() {
+        let target = Path::new("/home/a/a");
+        let files = [file("personal", 10), file("team", 0)];
+        assert_eq!(winner(target, &files).unwrap().layer, "personal");
+        assert!(winner(target, &[file("personal", 0), file("team", 0)]).is_err());
+        assert!(winner(target, &[]).is_err());
+    }
+
+    #[test]
+    fn deployed_blocks_side_by_side() {
+        let target = Path::new("/home/a/a");
+        let mut files = [file("personal", 10), file("team", 0)];
+        for f in &mut files {
+            f.mode = DeployMode::Block;
+        }
+        let layers = |files: &[ManagedFile]| {
+            deployed(target, files).map(|d| d.iter().map(|f| f.layer.clone()).collect::<Vec<_>>())
+        };
+        assert_eq!(layers(&files).unwrap(), ["personal", "team"]);
+
+        files[1].layer = "personal".into();
+        assert!(layers(&files).is_err());
+        files[1].mode = DeployMode::Copy;
+        assert!(layers(&files).is_err());
+    }
+
+    #[test]
+    fn relative_path_disjoint() {
+        let p = relative_path(Path::new("/home/a/b"), Path::new("/srv/dots/foo"));
+        assert_eq!(p, PathBuf::from("../../../srv/dots/foo"));
+    }
+}
@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+
+use crate::{
+    archive, fs, git,
+    lock::{self, LockFile},
+    source::{self, GitRef, Repo},
+};
+
+/// Clones a repo source into `dir` if it does not exist yet.
+///
+/// In locked mode, new clones are checked out at the commit recorded in the lockfile, and
+/// existing clones must already be at that commit.
+pub fn ensure(name: &str, repo: &Repo, dir: &Path) -> Result<()> {
+    let locked = if lock::is_locked() {
+        Some(LockFile::load()?.rev(name, repo)?.to_owned())
+    } else {
+        None
+    };
+
+    if !dir.is_dir() {
+        let url = source::repo_url(&repo.url)?;
+        info!("Cloning source `{name}` from `{url}`");
+        clone(repo, &url, dir)?;
+        if let Some(rev) = &locked {
+            git::checkout_detached(dir, rev)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(rev) = locked {
+        let head = git::revision(dir)?;
+        if head != rev {
+            bail!(
+                "source `{name}` is at {} but the lockfile requires {}, run `dfim sources sync --locked`",
+                short(&head),
+                short(&rev)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Clones or updates a repo source in `dir`, returning the commit that is checked out.
+///
+/// If `locked` is set, that commit is checked out instead of following the source reference.
+pub fn sync(name: &str, repo: &Repo, dir: &Path, locked: Option<&str>) -> Result<String> {
+    let url = source::repo_url(&repo.url)?;
+    if dir.is_dir() {
+        git::fetch(dir).with_context(|| format!("failed to fetch source `{name}`"))?;
+    } else {
+        info!("Cloning source `{name}` from `{url}`");
+        clone(repo, &url, dir)?;
+    }
+
+    match (locked, &repo.reference) {
+        (Some(rev), _) => git::checkout_detached(dir, rev)?,
+        (None, Some(GitRef::Branch(b))) => git::checkout_branch(dir, b)?,
+        (None, Some(GitRef::Tag(t))) => git::checkout_detached(dir, &format!("refs/tags/{t}"))?,
+        (None, Some(GitRef::Rev(r))) => git::checkout_detached(dir, r)?,
+        (None, None) => git::checkout_branch(dir, &git::default_branch(dir)?)?,
+    }
+
+    git::revision(dir)
+}
+
+/// Clones `url` into `dir`, checking out the source reference if set.
+fn clone(repo: &Repo, url: &str, dir: &Path) -> Result<()> {
+    match &repo.reference {
+        Some(GitRef::Branch(b) | GitRef::Tag(b)) => git::clone(url, dir, Some(b)),
+        Some(GitRef::Rev(r)) => {
+            git::clone(url, dir, None)?;
+            git::checkout_detached(dir, r)
+        }
+        None => git::clone(url, dir, None),
+    }
+}
+
+/// Extracts an archive source into `dir`, returning the archive checksum.
+///
+/// Extraction is skipped if the checksum recorded for `dir` matches the archive. In locked mode,
+/// the archive must match the checksum recorded in the lockfile.
+pub fn extract(name: &str, value: &str, dir: &Path) -> Result<String> {
+    let path = archive::resolve_path(value)?;
+    let checksum = fs::hash_file(&path)?;
+    if lock::is_locked() {
+        let locked = LockFile::load()?.sha256(name, value)?.to_owned();
+        if checksum != locked {
+            bail!(
+                "archive for source `{name}` has checksum {} but the lockfile requires {}",
+                short(&checksum),
+                short(&locked)
+            );
+        }
+    }
+
+    if dir.is_dir() && extracted_checksum(dir).as_ref() == Some(&checksum) {
+        return Ok(checksum);
+    }
+    info!("Extracting source `{name}` from `{}`", path.display());
+    archive::extract(&path, dir)?;
+    fs::write_atomic(&checksum_path(dir), checksum.as_bytes())?;
+
+    Ok(checksum)
+}
+
+/// Returns the checksum of the archive last extracted into `dir`, if any.
+pub fn extracted_checksum(dir: &Path) -> Option<String> {
+    std::fs::read_to_string(checksum_path(dir)).ok()
+}
+
+/// Returns the path of the file recording the checksum of an extracted archive.
+///
+/// This is kept next to `dir` so it is not deployed with the archive content.
+fn checksum_path(dir: &Path) -> PathBuf {
+    let name = dir.file_name().unwrap_or_default().to_string_lossy();
+    dir.with_file_name(format!(".{name}.sha256"))
+}
+
+/// Abbreviates a commit hash for display.
+pub fn short(rev: &str) -> &str {
+    &rev[..rev.len().min(7)]
+}
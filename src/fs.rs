@@ -0,0 +1,110 @@
+use std::{io::Write, path::Path};
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+
+/// Writes `content` to `path` by writing a temporary file in the same directory and renaming it.
+///
+/// Readers will either see the previous content or the new content, never a partial write.
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let parent = path
+        .parent()
+        .with_context(|| format!("path `{}` has no parent directory", path.display()))?;
+    std::fs::create_dir_all(parent)?;
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = parent.join(format!(".{name}.dfim-{}.tmp", std::process::id()));
+
+    let result = (|| -> Result<()> {
+        let mut f = std::fs::File::create(&tmp)?;
+        f.write_all(content)?;
+        f.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+
+    result.with_context(|| format!("failed to write `{}`", path.display()))
+}
+
+/// Moves a file, symlink, or directory to `dest`, creating parent directories as needed.
+///
+/// Files and symlinks are copied and removed if they cannot be renamed, e.g. when `dest` is on a
+/// different filesystem.
+pub fn move_path(path: &Path, dest: &Path) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if std::fs::rename(path, dest).is_ok() {
+        return Ok(());
+    }
+
+    let meta = std::fs::symlink_metadata(path)
+        .with_context(|| format!("failed to read `{}`", path.display()))?;
+    if meta.is_symlink() {
+        crate::deploy::symlink(&std::fs::read_link(path)?, dest)?;
+    } else if meta.is_file() {
+        std::fs::copy(path, dest)?;
+    } else {
+        bail!(
+            "failed to move directory `{}` to `{}`",
+            path.display(),
+            dest.display()
+        );
+    }
+    std::fs::remove_file(path).with_context(|| format!("failed to remove `{}`", path.display()))
+}
+
+/// Copies a file or symlink to `dest`, creating parent directories as needed.
+///
+/// Symlinks are copied as links, and files keep their permissions.
+pub fn copy_path(path: &Path, dest: &Path) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let meta = std::fs::symlink_metadata(path)
+        .with_context(|| format!("failed to read `{}`", path.display()))?;
+    let result = if meta.is_symlink() {
+        std::fs::read_link(path).and_then(|target| crate::deploy::symlink(&target, dest))
+    } else {
+        std::fs::copy(path, dest).map(|_| ())
+    };
+    result.with_context(|| {
+        format!(
+            "failed to copy `{}` to `{}`",
+            path.display(),
+            dest.display()
+        )
+    })
+}
+
+/// Returns the hex encoded SHA-256 digest of `content`.
+pub fn hash(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Returns the hex encoded SHA-256 digest of a file's content.
+pub fn hash_file(path: &Path) -> Result<String> {
+    let content =
+        std::fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+    Ok(hash(&content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_empty() {
+        assert_eq!(
+            hash(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::data_dir,
+    fs,
+    plan::{self, Action, Operation, Plan, Step},
+    state::{timestamp, Entry, State},
+};
+
+static KEEP: OnceLock<usize> = OnceLock::new();
+
+/// Sets how many generations are kept once a new one is recorded, see [`Generation::prune`].
+pub fn set_keep(keep: usize) -> Result<()> {
+    if KEEP.set(keep).is_err() {
+        bail!("failed to set generation limit");
+    }
+    Ok(())
+}
+
+/// Returns how many generations are kept, or `None` if the configuration was not loaded.
+pub fn keep() -> Option<usize> {
+    KEEP.get().copied()
+}
+
+/// The state of a path before a generation touched it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub layer: String,
+    /// Copy of the previous file or symlink, relative to the generation directory. This is `None`
+    /// if the path did not exist.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<PathBuf>,
+    /// Previous state file entry for the path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry: Option<Entry>,
+    /// Previous state file entries for blocks in the path, keyed by block name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub blocks: BTreeMap<String, Entry>,
+}
+
+/// A numbered record of an executed plan, with enough information to undo it.
+///
+/// Generations are stored in [`data_dir`] as `generations/<id>/generation.json`, along with
+/// copies of every file the plan replaced or removed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Generation {
+    pub id: u32,
+    /// Name of the command that executed the plan.
+    pub command: String,
+    #[serde(with = "timestamp")]
+    pub created_at: SystemTime,
+    /// Snapshots of every path touched by the plan.
+    pub paths: BTreeMap<PathBuf, Snapshot>,
+    /// Directories created by the plan, parents first.
+    #[serde(default)]
+    pub dirs: Vec<PathBuf>,
+    pub plan: Plan,
+}
+
+impl Generation {
+    /// Returns the directory containing all generations.
+    fn root() -> PathBuf {
+        data_dir().join("generations")
+    }
+
+    /// Returns the directory of this generation.
+    pub fn dir(&self) -> PathBuf {
+        Self::root().join(self.id.to_string())
+    }
+
+    /// Returns the ids of all recorded generations in ascending order.
+    fn ids() -> Result<Vec<u32>> {
+        let root = Self::root();
+        if !root.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut ids = vec![];
+        for entry in std::fs::read_dir(&root)
+            .with_context(|| format!("failed to read directory `{}`", root.display()))?
+        {
+            if let Some(id) = entry?.file_name().to_str().and_then(|s| s.parse().ok()) {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    fn load(id: u32) -> Result<Self> {
+        let path = Self::root().join(id.to_string()).join("generation.json");
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read generation `{}`", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("failed to parse generation `{}`", path.display()))
+    }
+
+    /// Loads every recorded generation in ascending order.
+    pub fn list() -> Result<Vec<Self>> {
+        Self::ids()?.into_iter().map(Self::load).collect()
+    }
+
+    /// Loads the most recent generation, if any.
+    pub fn latest() -> Result<Option<Self>> {
+        Self::ids()?.last().map(|id| Self::load(*id)).transpose()
+    }
+
+    /// Records a new generation for `plan` before it is executed by `command`.
+    ///
+    /// Every file or symlink the plan touches is copied into the generation directory, along with
+    /// its current entry in `state`.
+    pub fn create(command: &str, plan: &Plan, state: &State) -> Result<Self> {
+        let id = Self::ids()?.last().map_or(1, |id| id + 1);
+        let mut generation = Self {
+            id,
+            command: command.to_owned(),
+            created_at: SystemTime::now(),
+            paths: BTreeMap::new(),
+            dirs: vec![],
+            plan: plan.clone(),
+        };
+        let dir = generation.dir();
+        debug!("Recording generation {id} in `{}`", dir.display());
+
+        for step in &plan.steps {
+            for op in &step.operations {
+                match op {
+                    Operation::CreateDir { path } => {
+                        let mut created = path
+                            .ancestors()
+                            .take_while(|p| !p.exists())
+                            .map(Path::to_path_buf)
+                            .collect::<Vec<_>>();
+                        created.reverse();
+                        generation.dirs.extend(created);
+                    }
+                    // the previous content was moved out of the way, so restore it from there
+                    Operation::Backup { path, backup } => {
+                        generation.paths.entry(path.clone()).or_insert(Snapshot {
+                            layer: step.layer.clone().unwrap_or_default(),
+                            backup: Some(backup.clone()),
+                            entry: state.entries.get(path).cloned(),
+                            blocks: state.blocks.get(path).cloned().unwrap_or_default(),
+                        });
+                    }
+                    Operation::Adopt { path, source } => {
+                        generation.snapshot(
+                            &dir,
+                            step.layer.as_deref().unwrap_or_default(),
+                            path,
+                            state,
+                        )?;
+                        generation.snapshot(
+                            &dir,
+                            step.layer.as_deref().unwrap_or_default(),
+                            source,
+                            state,
+                        )?;
+                    }
+                    Operation::WriteFile { path, .. }
+                    | Operation::WriteBlock { path, .. }
+                    | Operation::CreateLink { path, .. }
+                    | Operation::Remove { path }
+                    | Operation::RemoveBlock { path, .. }
+                    | Operation::Chmod { path, .. }
+                    | Operation::Record { path, .. }
+                    | Operation::Forget { path }
+                    | Operation::ForgetBlock { path, .. } => generation.snapshot(
+                        &dir,
+                        step.layer.as_deref().unwrap_or_default(),
+                        path,
+                        state,
+                    )?,
+                    Operation::RunHook { .. }
+                    | Operation::Restore { .. }
+                    | Operation::RemoveDir { .. } => {}
+                }
+            }
+        }
+
+        let content = serde_json::to_string_pretty(&generation)?;
+        fs::write_atomic(&dir.join("generation.json"), content.as_bytes())?;
+        Ok(generation)
+    }
+
+    /// Copies `path` into `dir` the first time it is touched.
+    fn snapshot(&mut self, dir: &Path, layer: &str, path: &Path, state: &State) -> Result<()> {
+        if self.paths.contains_key(path) {
+            return Ok(());
+        }
+
+        let backup = match std::fs::symlink_metadata(path) {
+            Ok(m) if m.is_dir() => bail!(
+                "cannot record `{}` in generation, path is a directory",
+                path.display()
+            ),
+            Ok(_) => {
+                let backup = PathBuf::from("files").join(self.paths.len().to_string());
+                fs::copy_path(path, &dir.join(&backup))?;
+                Some(backup)
+            }
+            Err(_) => None,
+        };
+        self.paths.insert(
+            path.to_owned(),
+            Snapshot {
+                layer: layer.to_owned(),
+                backup,
+                entry: state.entries.get(path).cloned(),
+                blocks: state.blocks.get(path).cloned().unwrap_or_default(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Builds the plan that restores every path touched by this generation.
+    pub fn rollback(&self, state: &State) -> Result<Plan> {
+        let dir = self.dir();
+        let mut plan = Plan::default();
+
+        for (path, snapshot) in &self.paths {
+            let action = match snapshot.backup {
+                Some(_) => Action::Restore,
+                None => Action::Remove,
+            };
+            let mut step = Step::new(action, &snapshot.layer, path);
+
+            if std::fs::symlink_metadata(path).is_ok() {
+                step.operations
+                    .push(Operation::Remove { path: path.clone() });
+            }
+            if let Some(backup) = &snapshot.backup {
+                // backups of conflicting targets are absolute and replace `dir` here
+                step.operations.push(Operation::Restore {
+                    path: path.clone(),
+                    backup: dir.join(backup),
+                });
+            }
+            match (&snapshot.entry, state.entries.get(path)) {
+                (Some(prev), current) if current != Some(prev) => {
+                    step.operations.push(Operation::Record {
+                        path: path.clone(),
+                        entry: prev.clone(),
+                    })
+                }
+                (None, Some(_)) => step
+                    .operations
+                    .push(Operation::Forget { path: path.clone() }),
+                _ => {}
+            }
+            let current = state.blocks.get(path);
+            for (name, prev) in &snapshot.blocks {
+                if current.and_then(|b| b.get(name)) != Some(prev) {
+                    step.operations.push(Operation::Record {
+                        path: path.clone(),
+                        entry: prev.clone(),
+                    });
+                }
+            }
+            for name in current.into_iter().flat_map(|b| b.keys()) {
+                if !snapshot.blocks.contains_key(name) {
+                    step.operations.push(Operation::ForgetBlock {
+                        path: path.clone(),
+                        name: name.clone(),
+                    });
+                }
+            }
+
+            if !step.operations.is_empty() {
+                plan.steps.push(step);
+            }
+        }
+
+        // remove nested directories first
+        for path in self.dirs.iter().rev() {
+            if path.is_dir() {
+                let mut step = Step::dir(Action::Remove, path);
+                step.operations
+                    .push(Operation::RemoveDir { path: path.clone() });
+                plan.steps.push(step);
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Deletes the generation directory, including any copied files, and the backup directories
+    /// its plan moved conflicting targets into.
+    pub fn remove(&self) -> Result<()> {
+        let root = plan::backups_dir();
+        let backups = self
+            .plan
+            .steps
+            .iter()
+            .flat_map(|s| &s.operations)
+            .filter_map(|op| match op {
+                Operation::Backup { backup, .. } => backup.strip_prefix(&root).ok(),
+                _ => None,
+            })
+            .filter_map(|rel| rel.components().next())
+            .map(|c| root.join(c))
+            .collect::<BTreeSet<_>>();
+        for dir in backups.iter().filter(|d| d.is_dir()) {
+            std::fs::remove_dir_all(dir)
+                .with_context(|| format!("failed to remove backups `{}`", dir.display()))?;
+        }
+
+        let dir = self.dir();
+        std::fs::remove_dir_all(&dir)
+            .with_context(|| format!("failed to remove generation `{}`", dir.display()))
+    }
+
+    /// Removes the oldest generations so that at most `keep` remain, returning the removed
+    /// generations. A `keep` of 0 keeps every generation.
+    pub fn prune(keep: usize) -> Result<Vec<Self>> {
+        let ids = Self::ids()?;
+        if keep == 0 || ids.len() <= keep {
+            return Ok(vec![]);
+        }
+
+        let mut removed = vec![];
+        for id in &ids[..ids.len() - keep] {
+            let generation = Self::load(*id)?;
+            debug!("Pruning generation {id}");
+            generation.remove()?;
+            removed.push(generation);
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollback_restores_and_forgets() {
+        let target = PathBuf::from("/nonexistent/dfim/.bashrc");
+        let entry = Entry {
+            layer: "shell".into(),
+            source: "dots".into(),
+            source_path: "/nonexistent/dots/.bashrc".into(),
+            mode: crate::layer::DeployMode::Link,
+            hash: None,
+            deployed_at: SystemTime::UNIX_EPOCH,
+            updated_at: SystemTime::UNIX_EPOCH,
+        };
+        let mut state = State::default();
+        state.entries.insert(target.clone(), entry);
+
+        let generation = Generation {
+            id: 1,
+            command: "apply".into(),
+            created_at: SystemTime::UNIX_EPOCH,
+            paths: BTreeMap::from([(
+                target.clone(),
+                Snapshot {
+                    layer: "shell".into(),
+                    backup: Some("files/0".into()),
+                    entry: None,
+                    blocks: BTreeMap::new(),
+                },
+            )]),
+            dirs: vec![],
+            plan: Plan::default(),
+        };
+
+        let plan = generation.rollback(&state).unwrap();
+        assert_eq!(plan.steps.len(), 1);
+        assert_eq!(plan.steps[0].action, Action::Restore);
+        assert_eq!(
+            plan.steps[0].operations,
+            vec![
+                Operation::Restore {
+                    path: target.clone(),
+                    backup: generation.dir().join("files/0"),
+                },
+                Operation::Forget { path: target },
+            ]
+        );
+    }
+
+    #[test]
+    fn rollback_block_entries() {
+        let target = PathBuf::from("/nonexistent/dfim/.bashrc");
+        let entry = |layer: &str, hash: &str| Entry {
+            layer: layer.into(),
+            source: "dots".into(),
+            source_path: format!("/nonexistent/dots/{layer}.sh").into(),
+            mode: crate::layer::DeployMode::Block,
+            hash: Some(hash.into()),
+            deployed_at: SystemTime::UNIX_EPOCH,
+            updated_at: SystemTime::UNIX_EPOCH,
+        };
+        let mut state = State::default();
+        state.insert(&target, entry("shell", "new"));
+        state.insert(&target, entry("work", "new"));
+
+        let generation = Generation {
+            id: 1,
+            command: "apply".into(),
+            created_at: SystemTime::UNIX_EPOCH,
+            paths: BTreeMap::from([(
+                target.clone(),
+                Snapshot {
+                    layer: "shell".into(),
+                    backup: Some("files/0".into()),
+                    entry: None,
+                    blocks: BTreeMap::from([("shell".into(), entry("shell", "old"))]),
+                },
+            )]),
+            dirs: vec![],
+            plan: Plan::default(),
+        };
+
+        let plan = generation.rollback(&state).unwrap();
+        assert_eq!(
+            plan.steps[0].operations[1..],
+            [
+                Operation::Record {
+                    path: target.clone(),
+                    entry: entry("shell", "old"),
+                },
+                Operation::ForgetBlock {
+                    path: target,
+                    name: "work".into(),
+                },
+            ]
+        );
+    }
+}
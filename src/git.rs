@@ -0,0 +1,116 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+
+use crate::process::{self, RunOptions};
+
+/// Runs the system `git` with `args`, returning its trimmed `stdout`.
+pub fn git<S: AsRef<str>>(args: &[S], cwd: Option<&Path>) -> Result<String> {
+    let mut cmd = vec!["git"];
+    cmd.extend(args.iter().map(AsRef::as_ref));
+
+    let opts = RunOptions {
+        cwd: cwd.map(Path::to_owned),
+        // fail instead of waiting on credentials that will never be entered
+        env: HashMap::from([("GIT_TERMINAL_PROMPT".into(), "0".into())]),
+        ..Default::default()
+    };
+    let output = process::run(&cmd, &opts)?;
+    if !output.status.success() {
+        bail!(
+            "`{}` failed ({}):\n{}",
+            cmd.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end()
+        .to_owned())
+}
+
+/// Clones `url` into `dest`.
+///
+/// The repository is cloned into a temporary directory next to `dest` and renamed once complete,
+/// so `dest` never contains a partial clone.
+pub fn clone(url: &str, dest: &Path, branch: Option<&str>) -> Result<()> {
+    let parent = dest
+        .parent()
+        .with_context(|| format!("path `{}` has no parent directory", dest.display()))?;
+    std::fs::create_dir_all(parent)?;
+
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = parent.join(format!(".{name}.dfim-{}.tmp", std::process::id()));
+    if tmp.exists() {
+        std::fs::remove_dir_all(&tmp)?;
+    }
+
+    debug!("Cloning `{url}` into `{}`", dest.display());
+    let tmp_str = tmp.to_string_lossy();
+    let mut args = vec!["clone", "--quiet"];
+    if let Some(b) = branch {
+        args.extend(["--branch", b]);
+    }
+    args.extend([url, &tmp_str]);
+    let result = git(&args, None).and_then(|_| {
+        std::fs::rename(&tmp, dest)
+            .with_context(|| format!("failed to move clone to `{}`", dest.display()))
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_dir_all(&tmp);
+    }
+
+    result.with_context(|| format!("failed to clone `{url}`"))
+}
+
+/// Fetches branches and tags from `origin`.
+pub fn fetch(dir: &Path) -> Result<()> {
+    git(
+        &["fetch", "--quiet", "--tags", "--force", "origin"],
+        Some(dir),
+    )?;
+    Ok(())
+}
+
+/// Checks out `rev` with a detached head.
+pub fn checkout_detached(dir: &Path, rev: &str) -> Result<()> {
+    git(&["checkout", "--quiet", "--detach", rev], Some(dir))?;
+    Ok(())
+}
+
+/// Checks out `branch`, resetting it to the branch of the same name on `origin`.
+pub fn checkout_branch(dir: &Path, branch: &str) -> Result<()> {
+    let upstream = format!("origin/{branch}");
+    git(
+        &["checkout", "--quiet", "-B", branch, "--track", &upstream],
+        Some(dir),
+    )?;
+    Ok(())
+}
+
+/// Returns the name of the default branch on `origin`.
+pub fn default_branch(dir: &Path) -> Result<String> {
+    let head = git(
+        &["symbolic-ref", "--short", "refs/remotes/origin/HEAD"],
+        Some(dir),
+    )?;
+    Ok(head.strip_prefix("origin/").unwrap_or(&head).to_owned())
+}
+
+/// Returns the commit hash checked out in `dir`.
+pub fn revision(dir: &Path) -> Result<String> {
+    git(&["rev-parse", "HEAD"], Some(dir))
+}
+
+/// Returns the branch checked out in `dir`, or `None` if the head is detached.
+pub fn branch(dir: &Path) -> Result<Option<String>> {
+    let branch = git(&["rev-parse", "--abbrev-ref", "HEAD"], Some(dir))?;
+    Ok((branch != "HEAD").then_some(branch))
+}
+
+/// Returns `true` if the working tree in `dir` has uncommitted changes.
+pub fn is_dirty(dir: &Path) -> Result<bool> {
+    Ok(!git(&["status", "--porcelain"], Some(dir))?.is_empty())
+}
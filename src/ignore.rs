@@ -0,0 +1,150 @@
+use std::path::{Component, Path};
+
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+
+/// Name of the file in a source root listing additional ignore patterns.
+pub const IGNORE_FILE: &str = ".dfimignore";
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// A single ignore pattern.
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: Pattern,
+    /// Only match directories (the pattern ended with `/`).
+    dir_only: bool,
+    /// Match the whole relative path instead of only the file name (the pattern contained `/`).
+    anchored: bool,
+}
+
+/// Glob patterns for files in a source that are never deployed.
+///
+/// Patterns follow a subset of `.gitignore` rules: a trailing `/` only matches directories, and
+/// patterns containing any other `/` are matched against the path relative to the source root
+/// instead of the file name.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    /// Compiles a list of glob patterns.
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self> {
+        let mut rules = vec![];
+        for p in patterns {
+            let p = p.as_ref();
+            let dir_only = p.ends_with('/');
+            let trimmed = p.trim_end_matches('/');
+            let anchored = trimmed.contains('/');
+            let trimmed = trimmed.trim_start_matches('/');
+            let pattern =
+                Pattern::new(trimmed).with_context(|| format!("invalid ignore pattern `{p}`"))?;
+            rules.push(Rule {
+                pattern,
+                dir_only,
+                anchored,
+            });
+        }
+
+        Ok(Self { rules })
+    }
+
+    /// Compiles `patterns` along with any patterns in the [`IGNORE_FILE`] in `root`.
+    ///
+    /// Blank lines and lines starting with `#` in the ignore file are skipped.
+    pub fn load(root: &Path, patterns: &[String]) -> Result<Self> {
+        let mut patterns = patterns.to_vec();
+        let path = root.join(IGNORE_FILE);
+        if path.is_file() {
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read `{}`", path.display()))?;
+            patterns.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(ToOwned::to_owned),
+            );
+        }
+
+        Self::new(&patterns)
+            .with_context(|| format!("invalid ignore rules for `{}`", root.display()))
+    }
+
+    /// Returns `true` if the path `rel`, relative to the source root, or any of its parent
+    /// directories is ignored.
+    pub fn is_ignored(&self, rel: &Path, is_dir: bool) -> bool {
+        self.matches(rel, is_dir)
+            || rel
+                .ancestors()
+                .skip(1)
+                .filter(|a| !a.as_os_str().is_empty())
+                .any(|a| self.matches(a, true))
+    }
+
+    fn matches(&self, rel: &Path, is_dir: bool) -> bool {
+        if rel.file_name().is_some_and(|n| n == IGNORE_FILE) {
+            return true;
+        }
+
+        let full = rel
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(s) => Some(s.to_string_lossy()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let name = rel.file_name().unwrap_or_default().to_string_lossy();
+
+        self.rules.iter().any(|r| {
+            if r.dir_only && !is_dir {
+                return false;
+            }
+            let value = if r.anchored { &full } else { name.as_ref() };
+            r.pattern.matches_with(value, MATCH_OPTIONS)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_name_patterns() {
+        let rules = IgnoreRules::new(&["*.md", "LICENSE"]).unwrap();
+        assert!(rules.is_ignored(Path::new("README.md"), false));
+        assert!(rules.is_ignored(Path::new("docs/intro.md"), false));
+        assert!(rules.is_ignored(Path::new("LICENSE"), false));
+        assert!(!rules.is_ignored(Path::new(".bashrc"), false));
+    }
+
+    #[test]
+    fn dir_only_patterns() {
+        let rules = IgnoreRules::new(&["ci/"]).unwrap();
+        assert!(rules.is_ignored(Path::new("ci"), true));
+        assert!(rules.is_ignored(Path::new("ci/build.sh"), false));
+        assert!(!rules.is_ignored(Path::new("ci"), false));
+    }
+
+    #[test]
+    fn anchored_patterns() {
+        let rules = IgnoreRules::new(&["/.config/*.bak", "scripts/*"]).unwrap();
+        assert!(rules.is_ignored(Path::new(".config/foo.bak"), false));
+        assert!(!rules.is_ignored(Path::new(".config/nvim/foo.bak"), false));
+        assert!(rules.is_ignored(Path::new("scripts/setup.sh"), false));
+        assert!(!rules.is_ignored(Path::new("home/scripts/setup.sh"), false));
+    }
+
+    #[test]
+    fn ignore_file_is_ignored() {
+        let rules = IgnoreRules::default();
+        assert!(rules.is_ignored(Path::new(IGNORE_FILE), false));
+    }
+}
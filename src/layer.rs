@@ -0,0 +1,433 @@
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::bail;
+use clap::ValueEnum;
+use mlua::{Error as LuaError, FromLua, IntoLua, Lua, Result as LuaResult, Table, Value};
+use serde::{Deserialize, Serialize};
+
+/// Maps a path inside of a source to a path relative to the deployment root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMapping {
+    pub source: PathBuf,
+    pub target: PathBuf,
+}
+
+impl FileMapping {
+    /// Creates a mapping where the source and target paths are the same.
+    pub fn same<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        Self {
+            source: path.clone(),
+            target: path,
+        }
+    }
+}
+
+/// Controls how files in a layer are deployed to their targets.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeployMode {
+    /// Targets are symlinks pointing back into the source.
+    #[default]
+    Link,
+    /// Targets are copies of the source file, tracked by content hash.
+    Copy,
+    /// Targets keep their own content, with the source inserted between `dfim:<layer>` markers.
+    ///
+    /// Several layers can add blocks to the same target, since each block is named after its layer.
+    Block,
+}
+
+impl fmt::Display for DeployMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeployMode::Link => f.write_str("link"),
+            DeployMode::Copy => f.write_str("copy"),
+            DeployMode::Block => f.write_str("block"),
+        }
+    }
+}
+
+impl FromStr for DeployMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "link" => Ok(Self::Link),
+            "copy" => Ok(Self::Copy),
+            "block" => Ok(Self::Block),
+            _ => bail!("invalid deploy mode `{s}` (expected `link`, `copy`, or `block`)"),
+        }
+    }
+}
+
+/// Controls what happens when a target already exists and was not deployed by dfim.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Stop before making any changes.
+    #[default]
+    Abort,
+    /// Leave the existing target untouched.
+    Skip,
+    /// Move the existing target to a backup directory and deploy over it.
+    Backup,
+    /// Replace the source file with the existing target, then deploy it.
+    Adopt,
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictPolicy::Abort => f.write_str("abort"),
+            ConflictPolicy::Skip => f.write_str("skip"),
+            ConflictPolicy::Backup => f.write_str("backup"),
+            ConflictPolicy::Adopt => f.write_str("adopt"),
+        }
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(Self::Abort),
+            "skip" => Ok(Self::Skip),
+            "backup" => Ok(Self::Backup),
+            "adopt" => Ok(Self::Adopt),
+            _ => bail!(
+                "invalid conflict policy `{s}` (expected `abort`, `skip`, `backup`, or `adopt`)"
+            ),
+        }
+    }
+}
+
+/// Controls how symlink targets are written when deploying a layer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LinkStyle {
+    /// Links point to the absolute path of the source file.
+    #[default]
+    Absolute,
+    /// Links point to the source file relative to the link's parent directory.
+    Relative,
+}
+
+impl fmt::Display for LinkStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkStyle::Absolute => f.write_str("absolute"),
+            LinkStyle::Relative => f.write_str("relative"),
+        }
+    }
+}
+
+impl FromStr for LinkStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "absolute" => Ok(Self::Absolute),
+            "relative" => Ok(Self::Relative),
+            _ => bail!("invalid link style `{s}` (expected `absolute` or `relative`)"),
+        }
+    }
+}
+
+/// A named set of files drawn from a registered source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layer {
+    pub name: String,
+    pub source: String,
+    /// Files managed by this layer. An empty list means every file in the source.
+    pub files: Vec<FileMapping>,
+    pub mode: DeployMode,
+    pub link: LinkStyle,
+    /// Comment prefix for the markers of [`DeployMode::Block`] targets.
+    pub comment: Option<String>,
+    /// Overrides the global conflict policy for this layer.
+    pub on_conflict: Option<ConflictPolicy>,
+    /// Overrides the priority of the source when another layer provides the same target.
+    pub priority: Option<i64>,
+    /// Command to run after any file in this layer is created or updated.
+    pub hook: Vec<String>,
+}
+
+/// Checks that a path is relative and does not escape its root with `..` components.
+pub(crate) fn check_relative(path: &Path) -> Result<(), String> {
+    if path.as_os_str().is_empty() {
+        return Err("path must not be empty".into());
+    }
+    for c in path.components() {
+        match c {
+            Component::Normal(_) | Component::CurDir => {}
+            Component::ParentDir => {
+                return Err(format!("path `{}` must not contain `..`", path.display()))
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(format!("path `{}` must be relative", path.display()))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn conversion_error(from: &'static str, message: String) -> LuaError {
+    LuaError::FromLuaConversionError {
+        from,
+        to: "Layer",
+        message: Some(message),
+    }
+}
+
+fn files_from_table(t: &Table) -> LuaResult<Vec<FileMapping>> {
+    let mut files = vec![];
+    for pair in t.clone().pairs::<Value, String>() {
+        let (k, v) = pair?;
+        let mapping = match k {
+            Value::Integer(_) => FileMapping::same(v),
+            Value::String(s) => FileMapping {
+                source: PathBuf::from(v),
+                target: PathBuf::from(s.to_str()?),
+            },
+            other => {
+                return Err(conversion_error(
+                    "table",
+                    format!("unexpected `files` key type `{}`", other.type_name()),
+                ))
+            }
+        };
+        check_relative(&mapping.source).map_err(|e| conversion_error("table", e))?;
+        check_relative(&mapping.target).map_err(|e| conversion_error("table", e))?;
+        files.push(mapping);
+    }
+
+    Ok(files)
+}
+
+impl<'lua> FromLua<'lua> for Layer {
+    fn from_lua(value: Value<'lua>, _lua: &'lua Lua) -> LuaResult<Self> {
+        let Value::Table(ref t) = value else {
+            return Err(conversion_error(
+                value.type_name(),
+                "expected a table value".into(),
+            ));
+        };
+
+        let name = match t.get::<i32, Option<String>>(1)? {
+            Some(n) => n,
+            None => t
+                .get::<&str, Option<String>>("name")?
+                .ok_or_else(|| conversion_error("table", "expected [1] or `name` key".into()))?,
+        };
+        if name.is_empty() || name.chars().all(char::is_whitespace) {
+            return Err(conversion_error(
+                "table",
+                "layer name must not be empty or whitespace".into(),
+            ));
+        }
+
+        let source = t.get::<&str, Option<String>>("source")?.unwrap_or_default();
+        let files = match t.get::<&str, Value>("files")? {
+            Value::Nil => vec![],
+            Value::String(s) => vec![FileMapping::same(s.to_str()?)],
+            Value::Table(f) => files_from_table(&f)?,
+            other => {
+                return Err(conversion_error(
+                    "table",
+                    format!(
+                        "expected `files` to be a table, got `{}`",
+                        other.type_name()
+                    ),
+                ))
+            }
+        };
+
+        let mode = match t.get::<&str, Option<String>>("mode")? {
+            Some(m) => m
+                .parse()
+                .map_err(|e| conversion_error("table", format!("{e}")))?,
+            None => DeployMode::default(),
+        };
+        let link = match t.get::<&str, Option<String>>("link")? {
+            Some(l) => l
+                .parse()
+                .map_err(|e| conversion_error("table", format!("{e}")))?,
+            None => LinkStyle::default(),
+        };
+        let comment = t.get::<&str, Option<String>>("comment")?;
+        if comment.as_ref().is_some_and(|c| c.trim().is_empty()) {
+            return Err(conversion_error(
+                "table",
+                "comment must not be empty or whitespace".into(),
+            ));
+        }
+        let on_conflict = match t.get::<&str, Option<String>>("on_conflict")? {
+            Some(p) => Some(
+                p.parse()
+                    .map_err(|e| conversion_error("table", format!("{e}")))?,
+            ),
+            None => None,
+        };
+        let priority = t.get::<&str, Option<i64>>("priority")?;
+        let hook = match t.get::<&str, Value>("hook")? {
+            Value::Nil => vec![],
+            Value::String(s) => vec![s.to_str()?.to_owned()],
+            Value::Table(h) => h.sequence_values().collect::<LuaResult<_>>()?,
+            other => {
+                return Err(conversion_error(
+                    "table",
+                    format!("expected `hook` to be a table, got `{}`", other.type_name()),
+                ))
+            }
+        };
+
+        Ok(Self {
+            name,
+            source,
+            files,
+            mode,
+            link,
+            comment,
+            on_conflict,
+            priority,
+            hook,
+        })
+    }
+}
+
+impl<'lua> IntoLua<'lua> for Layer {
+    fn into_lua(self, lua: &'lua Lua) -> LuaResult<Value<'lua>> {
+        let t = lua.create_table()?;
+        t.set("name", self.name)?;
+        t.set("source", self.source)?;
+
+        let files = lua.create_table()?;
+        for f in self.files {
+            let src = f.source.to_string_lossy().to_string();
+            if f.source == f.target {
+                files.push(src)?;
+            } else {
+                files.set(f.target.to_string_lossy().to_string(), src)?;
+            }
+        }
+        t.set("files", files)?;
+        t.set("mode", self.mode.to_string())?;
+        t.set("link", self.link.to_string())?;
+        t.set("comment", self.comment)?;
+        t.set("on_conflict", self.on_conflict.map(|p| p.to_string()))?;
+        t.set("priority", self.priority)?;
+        if !self.hook.is_empty() {
+            t.set("hook", self.hook)?;
+        }
+
+        Ok(Value::Table(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(lua: &Lua, block: &str) -> LuaResult<Layer> {
+        lua.load(format!("return {block}")).call(())
+    }
+
+    #[test]
+    fn from_table_name() {
+        let lua = Lua::new();
+        let value = call(&lua, "{ 'foo', source = 'bar' }").unwrap();
+        assert_eq!(value.name, "foo");
+        assert_eq!(value.source, "bar");
+        assert!(value.files.is_empty());
+        assert_eq!(value.mode, DeployMode::Link);
+        assert_eq!(value.link, LinkStyle::Absolute);
+    }
+
+    #[test]
+    fn from_table_mode() {
+        let lua = Lua::new();
+        let value = call(&lua, "{ 'foo', mode = 'copy' }").unwrap();
+        assert_eq!(value.mode, DeployMode::Copy);
+        let value = call(&lua, "{ 'foo', mode = 'block', comment = '--' }").unwrap();
+        assert_eq!(value.mode, DeployMode::Block);
+        assert_eq!(value.comment.as_deref(), Some("--"));
+        assert!(call(&lua, "{ 'foo', mode = 'move' }").is_err());
+        assert!(call(&lua, "{ 'foo', comment = ' ' }").is_err());
+    }
+
+    #[test]
+    fn from_table_link() {
+        let lua = Lua::new();
+        let value = call(&lua, "{ 'foo', link = 'relative' }").unwrap();
+        assert_eq!(value.link, LinkStyle::Relative);
+        assert!(call(&lua, "{ 'foo', link = 'hard' }").is_err());
+    }
+
+    #[test]
+    fn from_table_on_conflict() {
+        let lua = Lua::new();
+        let value = call(&lua, "{ 'foo' }").unwrap();
+        assert_eq!(value.on_conflict, None);
+        let value = call(&lua, "{ 'foo', on_conflict = 'backup' }").unwrap();
+        assert_eq!(value.on_conflict, Some(ConflictPolicy::Backup));
+        assert!(call(&lua, "{ 'foo', on_conflict = 'merge' }").is_err());
+    }
+
+    #[test]
+    fn from_table_hook() {
+        let lua = Lua::new();
+        let value = call(&lua, "{ 'foo', hook = { 'fc-cache', '-f' } }").unwrap();
+        assert_eq!(value.hook, vec!["fc-cache", "-f"]);
+        let value = call(&lua, "{ 'foo', hook = 'true' }").unwrap();
+        assert_eq!(value.hook, vec!["true"]);
+    }
+
+    #[test]
+    fn from_table_priority() {
+        let lua = Lua::new();
+        let value = call(&lua, "{ 'foo' }").unwrap();
+        assert_eq!(value.priority, None);
+        let value = call(&lua, "{ 'foo', priority = 10 }").unwrap();
+        assert_eq!(value.priority, Some(10));
+        assert!(call(&lua, "{ 'foo', priority = 'high' }").is_err());
+    }
+
+    #[test]
+    fn from_table_files() {
+        let lua = Lua::new();
+        let value = call(
+            &lua,
+            "{ name = 'foo', files = { '.bashrc', ['.config/a'] = 'a' } }",
+        )
+        .unwrap();
+        assert_eq!(value.files.len(), 2);
+        assert!(value.files.contains(&FileMapping::same(".bashrc")));
+        assert!(value.files.contains(&FileMapping {
+            source: "a".into(),
+            target: ".config/a".into()
+        }));
+    }
+
+    #[test]
+    fn from_table_invalid_paths() {
+        let lua = Lua::new();
+        assert!(call(&lua, "{ 'foo', files = { '../foo' } }").is_err());
+        assert!(call(&lua, "{ 'foo', files = { '/etc/foo' } }").is_err());
+        assert!(call(&lua, "{ source = 'foo' }").is_err());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let lua = Lua::new();
+        let value = call(&lua, "{ 'foo', source = 'bar', files = { 'a', b = 'c' } }").unwrap();
+        let out: Layer = Layer::from_lua(value.clone().into_lua(&lua).unwrap(), &lua).unwrap();
+        assert_eq!(out.name, value.name);
+        assert_eq!(out.source, value.source);
+        assert_eq!(out.files.len(), value.files.len());
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{config_dir, portable_path, Config},
+    fs,
+    source::Repo,
+};
+
+static LOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Current version of the lockfile format.
+pub const LOCK_VERSION: u32 = 1;
+
+/// Requires repo sources to stay at the commits recorded in the lockfile.
+pub fn set_locked(value: bool) {
+    LOCKED.store(value, Ordering::Relaxed);
+}
+
+/// Returns `true` if repo sources must stay at the commits recorded in the lockfile.
+pub fn is_locked() -> bool {
+    LOCKED.load(Ordering::Relaxed)
+}
+
+/// The exact content a source was synced to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockedSource {
+    pub url: String,
+    /// Commit of a repo source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+    /// Checksum of an archive source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Commits of every repo source, stored as `dfim-lock.json` next to the configuration module.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LockFile {
+    pub version: u32,
+    #[serde(default)]
+    pub sources: BTreeMap<String, LockedSource>,
+}
+
+impl Default for LockFile {
+    fn default() -> Self {
+        Self {
+            version: LOCK_VERSION,
+            sources: BTreeMap::new(),
+        }
+    }
+}
+
+impl LockFile {
+    /// Returns the path of the lockfile.
+    pub fn path() -> PathBuf {
+        let dir = Config::get_module_file()
+            .and_then(|f| f.parent().map(ToOwned::to_owned))
+            .unwrap_or_else(|| config_dir().to_owned());
+        dir.join("dfim-lock.json")
+    }
+
+    /// Loads the lockfile, or returns an empty lockfile if it does not exist.
+    pub fn load() -> Result<Self> {
+        let path = Self::path();
+        if !path.is_file() {
+            debug!("No lockfile found at `{}`", path.display());
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read lockfile `{}`", path.display()))?;
+        let lock: Self = serde_json::from_str(&content)
+            .with_context(|| format!("failed to parse lockfile `{}`", path.display()))?;
+        if lock.version > LOCK_VERSION {
+            bail!(
+                "lockfile version {} is newer than supported version {LOCK_VERSION}",
+                lock.version
+            );
+        }
+        Ok(lock)
+    }
+
+    /// Writes the lockfile.
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        debug!("Saving lockfile to `{}`", path.display());
+        let mut content = serde_json::to_string_pretty(self)?;
+        content.push('\n');
+        fs::write_atomic(&path, content.as_bytes())
+    }
+
+    /// Removes entries for sources that are neither `synced` nor `disabled`.
+    ///
+    /// Disabled sources keep their entries, since they may be enabled on other machines sharing
+    /// the lockfile.
+    pub fn prune(&mut self, synced: &[&str], disabled: &[String]) {
+        self.sources
+            .retain(|name, _| synced.contains(&name.as_str()) || disabled.contains(name));
+    }
+
+    /// Records the commit or checksum a source was synced to.
+    pub fn lock(
+        &mut self,
+        name: &str,
+        url: &str,
+        rev: Option<String>,
+        sha256: Option<String>,
+    ) -> Result<()> {
+        let locked = LockedSource {
+            url: locked_url(url)?,
+            rev,
+            sha256,
+        };
+        self.sources.insert(name.to_owned(), locked);
+        Ok(())
+    }
+
+    /// Returns the lockfile entry for a source.
+    ///
+    /// It is an error if the source is not in the lockfile, or was locked with a different URL.
+    fn get(&self, name: &str, url: &str) -> Result<&LockedSource> {
+        let Some(locked) = self.sources.get(name) else {
+            bail!("source `{name}` is not in the lockfile, sync it without `--locked` first");
+        };
+        let url = locked_url(url)?;
+        if locked.url != url {
+            bail!(
+                "source `{name}` was locked with `{}` but is now `{url}`",
+                locked.url
+            );
+        }
+        Ok(locked)
+    }
+
+    /// Returns the locked commit for a repo source.
+    pub fn rev(&self, name: &str, repo: &Repo) -> Result<&str> {
+        self.get(name, &repo.url)?
+            .rev
+            .as_deref()
+            .with_context(|| format!("source `{name}` has no locked commit"))
+    }
+
+    /// Returns the locked checksum for an archive source.
+    pub fn sha256(&self, name: &str, url: &str) -> Result<&str> {
+        self.get(name, url)?
+            .sha256
+            .as_deref()
+            .with_context(|| format!("source `{name}` has no locked checksum"))
+    }
+}
+
+/// Returns the URL recorded for a source. Local paths are recorded relative to the configuration
+/// or home directory, since the lockfile is shared between machines.
+fn locked_url(url: &str) -> Result<String> {
+    let path = Path::new(url);
+    if !url.contains("://") && path.is_absolute() {
+        portable_path(path)
+    } else {
+        Ok(url.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_keeps_disabled() {
+        let locked = LockedSource {
+            url: "gh:a/b".into(),
+            rev: Some("abc".into()),
+            sha256: None,
+        };
+        let mut lock = LockFile::default();
+        for name in ["dots", "work", "removed"] {
+            lock.sources.insert(name.into(), locked.clone());
+        }
+
+        lock.prune(&["dots"], &["work".into()]);
+        let names = lock.sources.keys().collect::<Vec<_>>();
+        assert_eq!(names, ["dots", "work"]);
+    }
+}
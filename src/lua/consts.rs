@@ -1,8 +1,13 @@
 pub(crate) mod registry {
     pub(crate) mod flags {
+        pub(crate) const DATA_CHANGED: &str = "dfim-flag-data-changed";
         pub(crate) const LAYER_CREATED: &str = "dfim-flag-layer-created";
         pub(crate) const SOURCES_SET: &str = "dfim-flag-source-registered";
     }
 
+    pub(crate) const DATA: &str = "dfim-data";
+    pub(crate) const DISABLED_SOURCES: &str = "dfim-disabled-sources";
+    pub(crate) const LAYERS: &str = "dfim-layers";
     pub(crate) const SOURCES: &str = "dfim-sources";
+    pub(crate) const TEMPLATE_FILTERS: &str = "dfim-template-filters";
 }
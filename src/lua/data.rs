@@ -0,0 +1,231 @@
+use std::io::IsTerminal;
+
+use anyhow::Result;
+use log::{debug, trace};
+use mlua::{Error as LuaError, Lua, LuaSerdeExt, Result as LuaResult, Table, Value};
+use rustyline::{error::ReadlineError, DefaultEditor};
+use serde_json::Value as JValue;
+
+use crate::{
+    data::{self, DataStore},
+    lua::{
+        consts::registry::{flags::DATA_CHANGED, DATA},
+        get_registry_flag, set_registry_flag,
+    },
+};
+
+pub fn register<'lua>(lua: &'lua Lua, root: &'lua Table<'lua>) -> Result<()> {
+    trace!("Registering native module");
+    let proxy = lua.create_table()?;
+    let meta = lua.create_table()?;
+    meta.set(
+        "__index",
+        lua.create_function(|lua, (_, k): (Table, String)| load(lua)?.get::<_, Value>(k))?,
+    )?;
+    meta.set("__newindex", lua.create_function(set_value)?)?;
+    meta.set(
+        "__pairs",
+        lua.create_function(|lua, _: Table| {
+            let next: mlua::Function = lua.globals().get("next")?;
+            Ok((next, load(lua)?, Value::Nil))
+        })?,
+    )?;
+    proxy.set_metatable(Some(meta));
+    root.set("data", proxy)?;
+    root.set("prompt", lua.create_function(prompt)?)?;
+
+    Ok(())
+}
+
+/// Returns the table of stored values, loading the data file the first time it is used.
+fn load(lua: &Lua) -> LuaResult<Table<'_>> {
+    if let Value::Table(t) = lua.named_registry_value::<Value>(DATA)? {
+        return Ok(t);
+    }
+
+    let store = DataStore::load().map_err(|e| LuaError::runtime(format!("{e:#}")))?;
+    let t = lua.create_table()?;
+    for (k, v) in store.values {
+        t.set(k, lua.to_value(&v)?)?;
+    }
+    lua.set_named_registry_value(DATA, t.clone())?;
+    Ok(t)
+}
+
+/// Lua metamethod for assigning to `dfim.data`.
+///
+/// Only assignments to `dfim.data` are tracked, so nested tables must be assigned as a whole.
+fn set_value(lua: &Lua, (_, k, v): (Table, String, Value)) -> LuaResult<()> {
+    store(lua, k, v)
+}
+
+/// Stores a value in memory, marking the data as changed if it is different.
+///
+/// Changes are only written to the data file by [`save`].
+fn store<'lua>(lua: &'lua Lua, k: String, v: Value<'lua>) -> LuaResult<()> {
+    let t = load(lua)?;
+    let old: JValue = lua.from_value(t.get(k.as_str())?)?;
+    let new: JValue = lua.from_value(v.clone())?;
+    if old == new {
+        return Ok(());
+    }
+
+    t.set(k, v)?;
+    set_registry_flag(lua, DATA_CHANGED, true).map_err(LuaError::external)
+}
+
+/// Writes the data file if any value was changed since it was loaded.
+pub fn save(lua: &Lua) -> Result<()> {
+    if !get_registry_flag(lua, DATA_CHANGED) {
+        return Ok(());
+    }
+
+    let t = load(lua)?;
+    let store = DataStore {
+        values: lua.from_value(Value::Table(t))?,
+    };
+    store.save()?;
+    set_registry_flag(lua, DATA_CHANGED, false)
+}
+
+/// Options for `dfim.prompt`.
+#[derive(Debug)]
+struct Prompt {
+    name: String,
+    message: Option<String>,
+    default: Option<String>,
+    choices: Vec<String>,
+}
+
+impl Prompt {
+    fn from_table(t: &Table) -> LuaResult<Self> {
+        let name = match t.get::<_, Option<String>>(1)? {
+            Some(n) => n,
+            None => t
+                .get::<_, Option<String>>("name")?
+                .ok_or_else(|| LuaError::runtime("prompt must have a `name`"))?,
+        };
+        let prompt = Self {
+            name,
+            message: t.get("message")?,
+            default: t.get("default")?,
+            choices: t
+                .get::<_, Option<Vec<String>>>("choices")?
+                .unwrap_or_default(),
+        };
+        if let Some(d) = &prompt.default {
+            prompt.check(d)?;
+        }
+        Ok(prompt)
+    }
+
+    fn is_valid(&self, value: &str) -> bool {
+        self.choices.is_empty() || self.choices.iter().any(|c| c == value)
+    }
+
+    fn check(&self, value: &str) -> LuaResult<()> {
+        if self.is_valid(value) {
+            return Ok(());
+        }
+        Err(LuaError::runtime(format!(
+            "invalid answer `{value}` for prompt `{}` (expected one of: {})",
+            self.name,
+            self.choices.join(", ")
+        )))
+    }
+
+    /// Asks for a value on the terminal until a valid one is given.
+    fn ask(&self) -> LuaResult<String> {
+        let mut text = self.message.clone().unwrap_or_else(|| self.name.clone());
+        if !self.choices.is_empty() {
+            text.push_str(&format!(" ({})", self.choices.join("/")));
+        }
+        if let Some(d) = &self.default {
+            text.push_str(&format!(" [{d}]"));
+        }
+        text.push_str(": ");
+
+        let mut rl = DefaultEditor::new().map_err(LuaError::external)?;
+        loop {
+            let line = match rl.readline(&text) {
+                Ok(line) => line.trim().to_owned(),
+                Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
+                    return Err(LuaError::runtime(format!(
+                        "prompt `{}` was cancelled",
+                        self.name
+                    )))
+                }
+                Err(e) => return Err(LuaError::external(e)),
+            };
+
+            let value = match (line.is_empty(), &self.default) {
+                (true, Some(d)) => d.clone(),
+                (true, None) => continue,
+                (false, _) => line,
+            };
+            if self.is_valid(&value) {
+                return Ok(value);
+            }
+            eprintln!("expected one of: {}", self.choices.join(", "));
+        }
+    }
+}
+
+/// Lua function that returns the stored answer for a prompt, asking for it the first time.
+///
+/// Answers from the `--answers` file take precedence over stored values and are never stored.
+/// Without a terminal or answers file, the default is used without being stored.
+fn prompt<'lua>(lua: &'lua Lua, value: Table<'lua>) -> LuaResult<Value<'lua>> {
+    let prompt = Prompt::from_table(&value)?;
+
+    if let Some(answer) = data::answer(&prompt.name) {
+        if let JValue::String(s) = answer {
+            prompt.check(s)?;
+        }
+        return lua.to_value(answer);
+    }
+    match load(lua)?.get::<_, Value>(prompt.name.as_str())? {
+        Value::Nil => {}
+        stored => return Ok(stored),
+    }
+
+    if data::has_answers() || !std::io::stdin().is_terminal() {
+        return match &prompt.default {
+            Some(d) => {
+                debug!("Using default for prompt `{}`", prompt.name);
+                Ok(Value::String(lua.create_string(d)?))
+            }
+            None => Err(LuaError::runtime(format!(
+                "no answer for prompt `{}` (use --answers to answer prompts without a terminal)",
+                prompt.name
+            ))),
+        };
+    }
+    let answer = Value::String(lua.create_string(prompt.ask()?)?);
+    store(lua, prompt.name, answer.clone())?;
+    Ok(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prompt_choices() {
+        let lua = Lua::new();
+        let t: Table = lua
+            .load("return { 'kind', default = 'work', choices = { 'work', 'personal' } }")
+            .eval()
+            .unwrap();
+        let prompt = Prompt::from_table(&t).unwrap();
+        assert_eq!(prompt.name, "kind");
+        assert!(prompt.check("personal").is_ok());
+        assert!(prompt.check("other").is_err());
+
+        let t: Table = lua
+            .load("return { name = 'kind', default = 'x', choices = { 'work' } }")
+            .eval()
+            .unwrap();
+        assert!(Prompt::from_table(&t).is_err());
+    }
+}
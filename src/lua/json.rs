@@ -5,6 +5,8 @@ use log::trace;
 use mlua::{Error as LuaError, IntoLua, Lua, Result as LuaResult, Table, Value};
 use serde_json::Value as JValue;
 
+use crate::config;
+
 pub fn register<'lua>(lua: &'lua Lua, root: &'lua Table<'lua>) -> Result<()> {
     trace!("Registering native module");
     let m = lua.create_table()?;
@@ -26,24 +28,27 @@ fn to_json(_: &Lua, (value, pretty): (Value, bool)) -> LuaResult<String> {
     Ok(s)
 }
 
-fn from_json(lua: &Lua, value: String) -> LuaResult<Value> {
+fn from_json(lua: &Lua, value: String) -> LuaResult<Value<'_>> {
     let value: JValue = serde_json::from_str(&value).map_err(LuaError::external)?;
     from_json_impl(lua, value)
 }
 
-fn from_json_file(lua: &Lua, (value, buffered): (String, bool)) -> LuaResult<Value> {
+/// Lua function to decode a JSON file. Relative paths are resolved against the configuration
+/// module directory.
+fn from_json_file(lua: &Lua, (value, buffered): (String, bool)) -> LuaResult<Value<'_>> {
+    let path = config::expand_path(&value).map_err(|e| LuaError::runtime(format!("{e:#}")))?;
     if buffered {
-        let reader = BufReader::new(File::open(value)?);
+        let reader = BufReader::new(File::open(path)?);
         let value: JValue = serde_json::from_reader(reader).map_err(LuaError::external)?;
         from_json_impl(lua, value)
     } else {
-        from_json(lua, std::fs::read_to_string(value)?)
+        from_json(lua, std::fs::read_to_string(path)?)
     }
 }
 
 // adapted from wezterm, see:
 // https://github.com/wez/wezterm/blob/e5ac32f297cf3dd8f6ea280c130103f3cac4dddb/lua-api-crates/serde-funcs/src/lib.rs
-fn from_json_impl(lua: &Lua, value: JValue) -> LuaResult<Value> {
+fn from_json_impl(lua: &Lua, value: JValue) -> LuaResult<Value<'_>> {
     Ok(match value {
         JValue::Null => Value::Nil,
         JValue::Bool(b) => Value::Boolean(b),
@@ -0,0 +1,71 @@
+use anyhow::Result;
+use log::{debug, trace};
+use mlua::{Error as LuaError, FromLua, Lua, Result as LuaResult, Table, Value};
+
+use crate::{
+    layer::Layer,
+    lua::consts::registry::{flags::LAYER_CREATED, DISABLED_SOURCES, LAYERS},
+};
+
+pub fn register<'lua>(lua: &'lua Lua, root: &'lua Table<'lua>) -> Result<()> {
+    trace!("Registering native module");
+    lua.set_named_registry_value(LAYERS, Vec::<Layer>::new())?;
+
+    root.set("layer", lua.create_function(create_layer)?)?;
+    root.set("layers", lua.create_function(get_layers)?)?;
+
+    Ok(())
+}
+
+fn create_layer(lua: &Lua, value: Table) -> LuaResult<()> {
+    let mut layer = Layer::from_lua(Value::Table(value), lua)?;
+    let sources = super::source::load(lua)?;
+
+    // a single registered source is used by default
+    if layer.source.is_empty() {
+        if sources.len() != 1 {
+            return Err(LuaError::runtime(format!(
+                "layer `{}` must specify a source ({} sources registered)",
+                layer.name,
+                sources.len()
+            )));
+        }
+        layer.source = sources.keys().next().cloned().unwrap_or_default();
+    }
+    let disabled: Vec<String> = lua.named_registry_value(DISABLED_SOURCES)?;
+    if disabled.contains(&layer.source) {
+        debug!(
+            "Skipping layer `{}` (source `{}` is disabled)",
+            layer.name, layer.source
+        );
+        return Ok(());
+    }
+    if !sources.contains_key(&layer.source) {
+        return Err(LuaError::runtime(format!(
+            "layer `{}` uses unknown source `{}`",
+            layer.name, layer.source
+        )));
+    }
+
+    let mut layers: Vec<Layer> = lua.named_registry_value(LAYERS)?;
+    if layers.iter().any(|l| l.name == layer.name) {
+        return Err(LuaError::runtime(format!(
+            "layer name `{}` already exists",
+            layer.name
+        )));
+    }
+
+    debug!(
+        "Creating layer: `{}` (source `{}`)",
+        layer.name, layer.source
+    );
+    layers.push(layer);
+    lua.set_named_registry_value(LAYERS, layers)?;
+    super::set_registry_flag(lua, LAYER_CREATED, true).map_err(LuaError::runtime)?;
+
+    Ok(())
+}
+
+fn get_layers(lua: &Lua, _: ()) -> LuaResult<Vec<Layer>> {
+    lua.named_registry_value(LAYERS)
+}
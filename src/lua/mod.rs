@@ -1,10 +1,14 @@
 mod consts;
+mod data;
 mod json;
+mod layer;
 mod logging;
+mod plan;
 mod plugin;
 mod shared;
 mod source;
 mod system;
+mod template;
 mod traits;
 
 use std::path::Path;
@@ -13,19 +17,35 @@ use anyhow::{bail, Result};
 use log::{debug, trace};
 use mlua::{AsChunk, FromLua, Lua, Table, Value};
 
-use crate::{config::config_dir, path};
+use crate::{
+    config::{config_dir, home_dir},
+    deploy::{self, ManagedFile},
+    layer::Layer,
+    path,
+};
+
+use self::consts::registry;
+pub(crate) use self::{
+    source::SourceMap,
+    system::{set_fact_overrides, FactOverrides},
+    template::render_file,
+};
 
 static MOD_NAME: &str = env!("CARGO_PKG_NAME");
 
 type RegisterFn = for<'lua> fn(&'lua Lua, &'lua Table<'lua>) -> Result<()>;
 
-const REGISTER_FNS: [RegisterFn; 6] = [
+const REGISTER_FNS: [RegisterFn; 10] = [
+    data::register,
     json::register,
+    layer::register,
     logging::register,
+    plan::register,
     plugin::register,
     shared::register,
     source::register,
     system::register,
+    template::register,
 ];
 
 // This file is generated by the build script (build.rs). It creates a const array of pairs with
@@ -42,6 +62,7 @@ pub fn create_state() -> Result<Lua> {
         update_package_path(&lua)?;
         let m = create_module(&lua, MOD_NAME)?;
         m.set("version", env!("CARGO_PKG_VERSION"))?;
+        m.set("options", lua.create_table()?)?;
         create_native_api(&lua, &m)?;
 
         lua.globals().set(MOD_NAME, m)?;
@@ -181,3 +202,71 @@ pub(crate) fn set_registry_flag(lua: &Lua, key: &str, value: bool) -> Result<()>
     lua.set_named_registry_value(key, value)?;
     Ok(())
 }
+
+/// Returns the sources registered with `dfim.sources.set`.
+pub(crate) fn get_sources(lua: &Lua) -> Result<SourceMap> {
+    Ok(source::load(lua)?)
+}
+
+/// Returns the names of sources skipped because their `enabled` predicate was false.
+pub(crate) fn get_disabled_sources(lua: &Lua) -> Result<Vec<String>> {
+    Ok(lua.named_registry_value(registry::DISABLED_SOURCES)?)
+}
+
+/// Returns the layers created with `dfim.layer`, in order of creation.
+pub(crate) fn get_layers(lua: &Lua) -> Result<Vec<Layer>> {
+    Ok(lua.named_registry_value(registry::LAYERS)?)
+}
+
+/// Writes the values changed through `dfim.data` and `dfim.prompt` to the data file.
+pub(crate) fn save_data(lua: &Lua) -> Result<()> {
+    data::save(lua)
+}
+
+/// Returns the named layers, or all layers if `names` is empty.
+fn select_layers(lua: &Lua, names: &[String]) -> Result<Vec<Layer>> {
+    let mut layers = get_layers(lua)?;
+    for name in names {
+        if !layers.iter().any(|l| &l.name == name) {
+            bail!("layer `{name}` does not exist");
+        }
+    }
+    if !names.is_empty() {
+        layers.retain(|l| names.contains(&l.name));
+    }
+    Ok(layers)
+}
+
+/// Fetches the repo and archive sources used by any layer.
+///
+/// Every source is needed even when only some layers are selected, since layers that are not
+/// selected can still shadow their targets, see [`managed_files`].
+pub(crate) fn fetch_sources(lua: &Lua) -> Result<()> {
+    let sources = get_sources(lua)?;
+    for layer in get_layers(lua)? {
+        if let Some(spec) = sources.get(&layer.source) {
+            deploy::fetch_source(&layer.source, &spec.source)?;
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the files managed by the named layers, or by all layers if `names` is empty.
+///
+/// Precedence between layers is worked out with every layer, so a named layer never deploys a
+/// target shadowed by another layer. Sources are never fetched, see [`fetch_sources`].
+pub(crate) fn managed_files(lua: &Lua, names: &[String]) -> Result<(Vec<Layer>, Vec<ManagedFile>)> {
+    let sources = get_sources(lua)?;
+    let layers = select_layers(lua, names)?;
+
+    let mut files = deploy::collect(&get_layers(lua)?, &sources, home_dir())?;
+    files.retain(|f| layers.iter().any(|l| l.name == f.layer));
+    for file in &mut files {
+        if crate::template::is_template(&file.source_path) {
+            let root = deploy::include_root(&file.source, &sources[&file.source].source)?;
+            let content = template::render_file(lua, &file.source_path, &root)?;
+            file.content = Some(content.into_bytes());
+        }
+    }
+    Ok((layers, files))
+}
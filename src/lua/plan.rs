@@ -0,0 +1,35 @@
+use anyhow::Result;
+use log::trace;
+use mlua::{Error as LuaError, Lua, LuaSerdeExt, Result as LuaResult, Table, Value};
+
+use crate::{
+    config::Config,
+    plan::{self, ApplyOptions},
+    state::State,
+};
+
+pub fn register<'lua>(lua: &'lua Lua, root: &'lua Table<'lua>) -> Result<()> {
+    trace!("Registering native module");
+    root.set("plan", lua.create_function(apply_plan)?)?;
+
+    Ok(())
+}
+
+/// Lua function returning the plan `dfim apply` would execute for the given layers.
+fn apply_plan(lua: &Lua, names: Option<Vec<String>>) -> LuaResult<Value<'_>> {
+    let names = names.unwrap_or_default();
+    let plan = (|| {
+        let (layers, files) = super::managed_files(lua, &names)?;
+        let config = Config::from_lua(lua)?;
+        let state = State::load()?;
+        let options = ApplyOptions {
+            on_conflict: config.on_conflict,
+            force_conflict: None,
+            backup_dir: plan::new_backup_dir(),
+        };
+        plan::apply(&layers, &files, &state, &options)
+    })()
+    .map_err(|e| LuaError::runtime(format!("{e:#}")))?;
+
+    lua.to_value(&plan)
+}
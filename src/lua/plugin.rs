@@ -4,7 +4,7 @@ use mlua::{Lua, MultiValue, Result as LuaResult, Table, Value};
 
 use crate::{config::plugin_dir, path, pathsep};
 
-pub fn register<'lua>(lua: &'lua Lua, _: &'lua Table) -> Result<()> {
+pub fn register<'lua>(lua: &'lua Lua, _: &'lua Table<'lua>) -> Result<()> {
     trace!("Setting plugin package searcher");
     let package: Table = lua.globals().get("package")?;
     let searchers: Table = package.get("searchers")?;
@@ -13,7 +13,7 @@ pub fn register<'lua>(lua: &'lua Lua, _: &'lua Table) -> Result<()> {
     Ok(())
 }
 
-fn search_plugins(lua: &Lua, modname: String) -> LuaResult<MultiValue> {
+fn search_plugins(lua: &Lua, modname: String) -> LuaResult<MultiValue<'_>> {
     let plugin_dir = plugin_dir();
     trace!("Searching for plugin module `{modname}`");
 
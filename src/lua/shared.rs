@@ -34,7 +34,7 @@ fn split(
 }
 
 /// Lua function for trimming whitespace from a string.
-fn trim(lua: &Lua, value: String) -> LuaResult<mlua::String> {
+fn trim(lua: &Lua, value: String) -> LuaResult<mlua::String<'_>> {
     lua.create_string(value.trim())
 }
 
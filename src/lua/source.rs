@@ -1,22 +1,26 @@
-use std::collections::HashMap;
-
 use anyhow::Result;
+use indexmap::IndexMap;
 use log::{debug, trace, warn};
 use mlua::{Error as LuaError, FromLua, Lua, Result as LuaResult, Table, Value};
 
 use crate::{
-    lua::consts::registry::{
-        flags::{LAYER_CREATED, SOURCES_SET},
-        SOURCES,
+    lua::{
+        consts::registry::{
+            flags::{LAYER_CREATED, SOURCES_SET},
+            DISABLED_SOURCES, SOURCES,
+        },
+        traits::LuaFlexValue,
     },
-    source::Source,
+    source::SourceSpec,
 };
 
-type SourceMap = HashMap<String, Source>;
+/// Registered sources by name, in the order they were declared.
+pub(crate) type SourceMap = IndexMap<String, SourceSpec>;
 
 pub fn register<'lua>(lua: &'lua Lua, root: &'lua Table<'lua>) -> Result<()> {
     trace!("Registering native module");
-    lua.set_named_registry_value(SOURCES, SourceMap::new())?;
+    store(lua, &SourceMap::new())?;
+    lua.set_named_registry_value(DISABLED_SOURCES, Vec::<String>::new())?;
 
     let m = lua.create_table()?;
     m.set("set", lua.create_function(set_sources)?)?;
@@ -36,44 +40,86 @@ fn set_sources(lua: &Lua, value: Table) -> LuaResult<()> {
         warn!("setting sources multiple times is not recommended (source map is cleared on each call)")
     }
 
-    let mut sources: SourceMap = lua.named_registry_value(SOURCES)?;
-    sources.clear();
+    let mut sources = SourceMap::new();
+    let mut disabled: Vec<String> = vec![];
+    let mut facts = None;
 
     let values = value.sequence_values::<Value>();
     for src in values {
         let src = src?;
-        let v = Source::from_lua(src.clone(), lua)?;
-        let k = if let Value::Table(t) = src {
-            if let Ok(name) = t.get::<_, String>("name") {
-                name
-            } else {
-                v.name()
-            }
+        let mut v = SourceSpec::from_lua(src.clone(), lua)?;
+        // resolve before deriving the name so values like `.` are named after the directory
+        v.source = v
+            .source
+            .resolve_paths()
+            .map_err(|e| LuaError::runtime(format!("{e:#}")))?;
+        let k = if let Value::Table(ref t) = src {
+            String::maybe_flex_value(lua, t.get("name")?, ()).unwrap_or_else(|| v.source.name())
         } else {
-            v.name()
+            v.source.name()
         };
 
-        debug!("Registering source: `{k}` => `{v}`");
         if k.is_empty() {
             return Err(LuaError::runtime("source name must not be empty"));
         }
-        if sources.contains_key(&k) {
+        if sources.contains_key(&k) || disabled.contains(&k) {
             return Err(LuaError::runtime(format!(
                 "source name `{k}` already exists"
             )));
         }
 
+        if let Value::Table(ref t) = src {
+            let enabled = t.get::<_, Value>("enabled")?;
+            if !enabled.is_nil() {
+                let facts = match &facts {
+                    Some(f) => f,
+                    None => facts.insert(super::system::facts(lua)?),
+                };
+                if !bool::flex_value(lua, enabled, facts.clone())? {
+                    debug!("Skipping disabled source: `{k}`");
+                    disabled.push(k);
+                    continue;
+                }
+            }
+        }
+
+        debug!("Registering source: `{k}` => `{}`", v.source);
         sources.insert(k, v);
     }
 
-    lua.set_named_registry_value(SOURCES, sources)?;
+    store(lua, &sources)?;
+    lua.set_named_registry_value(DISABLED_SOURCES, disabled)?;
     super::set_registry_flag(lua, SOURCES_SET, true).map_err(LuaError::runtime)?;
 
     Ok(())
 }
 
-fn get_sources(lua: &Lua, _: ()) -> LuaResult<Table> {
-    let sources: SourceMap = lua.named_registry_value(SOURCES)?;
+/// Saves the source map in the registry as a sequence of `{ name, spec }` pairs, since lua tables
+/// do not keep the order of their keys.
+fn store(lua: &Lua, sources: &SourceMap) -> LuaResult<()> {
+    let seq = lua.create_table()?;
+    for (k, v) in sources {
+        let pair = lua.create_table()?;
+        pair.set("name", k.as_str())?;
+        pair.set("spec", v.clone())?;
+        seq.push(pair)?;
+    }
+    lua.set_named_registry_value(SOURCES, seq)
+}
+
+/// Loads the source map saved in the registry.
+pub(crate) fn load(lua: &Lua) -> LuaResult<SourceMap> {
+    let seq: Table = lua.named_registry_value(SOURCES)?;
+    seq.sequence_values::<Table>()
+        .map(|pair| {
+            let pair = pair?;
+            Ok((pair.get("name")?, pair.get("spec")?))
+        })
+        .collect()
+}
+
+fn get_sources(lua: &Lua, _: ()) -> LuaResult<Table<'_>> {
+    let sources = load(lua)?;
     let table = lua.create_table()?;
 
     for (k, v) in sources {
@@ -82,3 +128,34 @@ fn get_sources(lua: &Lua, _: ()) -> LuaResult<Table> {
 
     Ok(table)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enabled_predicate() {
+        let lua = crate::lua::create_state().unwrap();
+        lua.load(
+            r#"
+            dfim.sources.set {
+                { dir = 'a', enabled = true },
+                { dir = 'b', enabled = false },
+                { dir = 'c', enabled = function(f) return f.os == dfim.os_name end },
+            }
+            dfim.layer { name = 'b', source = 'b' }
+            "#,
+        )
+        .exec()
+        .unwrap();
+
+        let sources = load(&lua).unwrap();
+        let mut names = sources.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["a", "c"]);
+        let layers: Vec<crate::layer::Layer> = lua
+            .named_registry_value(crate::lua::consts::registry::LAYERS)
+            .unwrap();
+        assert!(layers.is_empty());
+    }
+}
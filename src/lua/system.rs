@@ -1,17 +1,61 @@
-use std::{collections::HashMap, path::PathBuf, process::Command};
+use std::sync::OnceLock;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use log::trace;
 use mlua::{Error as LuaError, Lua, LuaSerdeExt, Table, Value};
-use serde::Deserialize;
+
+use crate::{
+    config,
+    process::{self, RunOptions},
+};
+
+static OVERRIDES: OnceLock<FactOverrides> = OnceLock::new();
+
+/// System facts that replace the real values, used to preview how the configuration behaves on
+/// another machine.
+#[derive(Debug, Default, Clone)]
+pub struct FactOverrides {
+    pub os: Option<String>,
+    pub arch: Option<String>,
+    pub hostname: Option<String>,
+}
+
+/// Replaces system facts in every lua state created afterwards.
+pub fn set_fact_overrides(overrides: FactOverrides) -> Result<()> {
+    if OVERRIDES.set(overrides).is_err() {
+        bail!("failed to set system fact overrides");
+    }
+    Ok(())
+}
+
+fn overrides() -> &'static FactOverrides {
+    OVERRIDES.get_or_init(FactOverrides::default)
+}
+
+fn os_name() -> &'static str {
+    overrides().os.as_deref().unwrap_or(std::env::consts::OS)
+}
+
+fn arch() -> &'static str {
+    overrides()
+        .arch
+        .as_deref()
+        .unwrap_or(std::env::consts::ARCH)
+}
 
 pub fn register<'lua>(lua: &'lua Lua, root: &'lua Table<'lua>) -> Result<()> {
     trace!("Registering native module");
+    let os = os_name();
+    let family = match overrides().os {
+        Some(_) if os == "windows" => "windows",
+        Some(_) => "unix",
+        None => std::env::consts::FAMILY,
+    };
     root.set("target_triple", env!("VERGEN_CARGO_TARGET_TRIPLE"))?;
-    root.set("os_name", std::env::consts::OS)?;
-    root.set("os_family", std::env::consts::FAMILY)?;
-    root.set("arch", std::env::consts::ARCH)?;
-    root.set("is_windows", std::env::consts::OS == "windows")?;
+    root.set("os_name", os)?;
+    root.set("os_family", family)?;
+    root.set("arch", arch())?;
+    root.set("is_windows", os == "windows")?;
     root.set("is_wsl", option_env!("DFIM_WSL").is_some())?;
     root.set("spawn", lua.create_function(spawn)?)?;
     root.set("hostname", lua.create_function(hostname)?)?;
@@ -20,19 +64,9 @@ pub fn register<'lua>(lua: &'lua Lua, root: &'lua Table<'lua>) -> Result<()> {
     Ok(())
 }
 
-#[derive(Default, Deserialize)]
-struct RunOptions {
-    #[serde(default)]
-    cwd: Option<PathBuf>,
-    #[serde(default)]
-    env: HashMap<String, String>,
-    #[serde(default)]
-    clear_env: Option<bool>,
-}
-
 pub(crate) fn spawn<'lua>(
     lua: &'lua Lua,
-    (mut args, opts): (Vec<String>, Option<Table>),
+    (args, opts): (Vec<String>, Option<Table>),
 ) -> mlua::Result<Table<'lua>> {
     if args.is_empty() {
         return Err(LuaError::RuntimeError(
@@ -40,25 +74,17 @@ pub(crate) fn spawn<'lua>(
         ));
     }
 
-    let prog = args.remove(0);
-    let opts = opts
+    let mut opts: RunOptions = opts
         .map(|t| lua.from_value(Value::Table(t)))
         .unwrap_or_else(|| Ok(RunOptions::default()))?;
-
-    let mut cmd = Command::new(prog);
-    if !args.is_empty() {
-        cmd.args(args);
-    }
-    if let Some(cwd) = opts.cwd {
-        cmd.current_dir(cwd);
+    if let Some(cwd) = &opts.cwd {
+        let cwd = config::expand_path(&cwd.to_string_lossy())
+            .map_err(|e| LuaError::RuntimeError(format!("{e:#}")))?;
+        opts.cwd = Some(cwd);
     }
-    if opts.clear_env.unwrap_or_default() {
-        cmd.env_clear();
-    }
-    cmd.envs(&opts.env);
-    let output = cmd
-        .output()
-        .map_err(|e| LuaError::RuntimeError(e.to_string()))?;
+
+    let output =
+        process::run(&args, &opts).map_err(|e| LuaError::RuntimeError(format!("{e:#}")))?;
 
     let tbl = lua.create_table()?;
     tbl.set("success", output.status.success())?;
@@ -69,12 +95,26 @@ pub(crate) fn spawn<'lua>(
     Ok(tbl)
 }
 
+/// Returns a table of facts about the current system, passed to predicates such as the `enabled`
+/// key of sources.
+pub(crate) fn facts(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let t = lua.create_table()?;
+    t.set("os", os_name())?;
+    t.set("arch", arch())?;
+    t.set("hostname", hostname(lua, ())?)?;
+    t.set("wsl", option_env!("DFIM_WSL").is_some())?;
+    Ok(t)
+}
+
 /// Lua function to get the system hostname.
 ///
 /// This function is adapted from [`wezterm`].
 ///
 /// [`wezterm`]: https://github.com/wez/wezterm/blob/e5ac32f297cf3dd8f6ea280c130103f3cac4dddb/config/src/lua.rs#L427-L433
 fn hostname(_: &Lua, _: ()) -> mlua::Result<String> {
+    if let Some(h) = &overrides().hostname {
+        return Ok(h.clone());
+    }
     hostname::get()
         .map_err(mlua::Error::external)?
         .to_str()
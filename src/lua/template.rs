@@ -0,0 +1,247 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::trace;
+use mlua::{Error as LuaError, Function, Lua, MultiValue, Result as LuaResult, Table, Value};
+
+use crate::{config, deploy, lua::consts::registry::TEMPLATE_FILTERS, template};
+
+/// Limit for nested includes, which stops templates that include themselves.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+pub fn register<'lua>(lua: &'lua Lua, root: &'lua Table<'lua>) -> Result<()> {
+    trace!("Registering native module");
+    let filters = lua.create_table()?;
+    filters.set(
+        "upper",
+        lua.create_function(|_, s: String| Ok(s.to_uppercase()))?,
+    )?;
+    filters.set(
+        "lower",
+        lua.create_function(|_, s: String| Ok(s.to_lowercase()))?,
+    )?;
+    filters.set(
+        "trim",
+        lua.create_function(|_, s: String| Ok(s.trim().to_owned()))?,
+    )?;
+    filters.set("default", lua.create_function(default)?)?;
+    filters.set("join", lua.create_function(join)?)?;
+    lua.set_named_registry_value(TEMPLATE_FILTERS, filters)?;
+
+    let m = lua.create_table()?;
+    m.set("vars", lua.create_table()?)?;
+    m.set("render", lua.create_function(render_string)?)?;
+    m.set("filter", lua.create_function(set_filter)?)?;
+    root.set("template", m)?;
+
+    Ok(())
+}
+
+/// Renders template `text` with the variables in `dfim.template.vars`, followed by `vars`.
+///
+/// Templates run in the same state as the configuration, so any global or loaded module is
+/// available. Variables shadow globals with the same name. Paths passed to `include` are relative
+/// to `dir`, and must not point outside of `root`.
+pub(crate) fn render(
+    lua: &Lua,
+    name: &str,
+    text: &str,
+    vars: Option<Table>,
+    dir: &Path,
+    root: &Path,
+) -> LuaResult<String> {
+    let module: Table = lua
+        .globals()
+        .get::<_, Table>(env!("CARGO_PKG_NAME"))?
+        .get("template")?;
+    let merged = lua.create_table()?;
+    for t in [module.get::<_, Option<Table>>("vars")?, vars]
+        .into_iter()
+        .flatten()
+    {
+        for pair in t.pairs::<Value, Value>() {
+            let (k, v) = pair?;
+            merged.raw_set(k, v)?;
+        }
+    }
+
+    render_with(lua, name, text, merged, dir.to_owned(), root.to_owned(), 0)
+}
+
+fn render_with(
+    lua: &Lua,
+    name: &str,
+    text: &str,
+    vars: Table,
+    dir: PathBuf,
+    root: PathBuf,
+    depth: usize,
+) -> LuaResult<String> {
+    let chunk = template::compile(text).map_err(|e| LuaError::runtime(format!("{name}: {e:#}")))?;
+
+    let env = lua.create_table()?;
+    for pair in vars.clone().pairs::<Value, Value>() {
+        let (k, v) = pair?;
+        env.raw_set(k, v)?;
+    }
+    env.raw_set("__filter", lua.create_function(apply_filter)?)?;
+    env.raw_set("include", include_fn(lua, vars, dir, root, depth)?)?;
+    let meta = lua.create_table()?;
+    meta.set("__index", lua.globals())?;
+    env.set_metatable(Some(meta));
+
+    lua.load(chunk).set_name(name).set_environment(env).call(())
+}
+
+/// Creates the `include(path, vars)` function for a template, which renders another template
+/// relative to `dir` with the same variables, followed by `vars`.
+///
+/// Included paths must be relative and stay inside of `root`.
+fn include_fn<'lua>(
+    lua: &'lua Lua,
+    vars: Table<'lua>,
+    dir: PathBuf,
+    root: PathBuf,
+    depth: usize,
+) -> LuaResult<Function<'lua>> {
+    let key = lua.create_registry_value(vars)?;
+    lua.create_function(move |lua, (path, extra): (String, Option<Table>)| {
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(LuaError::runtime(format!(
+                "cannot include `{path}`, templates are nested more than {MAX_INCLUDE_DEPTH} levels deep"
+            )));
+        }
+
+        if Path::new(&path).is_absolute() {
+            return Err(LuaError::runtime(format!(
+                "cannot include `{path}`, path must be relative"
+            )));
+        }
+        let path = deploy::normalize(&dir.join(&path));
+        if !path.starts_with(&root) {
+            return Err(LuaError::runtime(format!(
+                "cannot include `{}`, path is outside of `{}`",
+                path.display(),
+                root.display()
+            )));
+        }
+        let text = std::fs::read_to_string(&path).map_err(|e| {
+            LuaError::runtime(format!("failed to read `{}`: {e}", path.display()))
+        })?;
+
+        let parent: Table = lua.registry_value(&key)?;
+        let vars = lua.create_table()?;
+        for t in [Some(parent), extra].into_iter().flatten() {
+            for pair in t.pairs::<Value, Value>() {
+                let (k, v) = pair?;
+                vars.raw_set(k, v)?;
+            }
+        }
+
+        let dir = path.parent().map(ToOwned::to_owned).unwrap_or_default();
+        render_with(
+            lua,
+            &path.to_string_lossy(),
+            &text,
+            vars,
+            dir,
+            root.clone(),
+            depth + 1,
+        )
+    })
+}
+
+/// Reads and renders the template at `path`, which can include files inside of `root`.
+pub(crate) fn render_file(lua: &Lua, path: &Path, root: &Path) -> Result<String> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read template `{}`", path.display()))?;
+    let name = path.to_string_lossy();
+    let dir = path.parent().unwrap_or(Path::new(""));
+    render(lua, &name, &text, None, dir, root)
+        .with_context(|| format!("failed to render template `{}`", path.display()))
+}
+
+/// Lua function to render a template string. Includes are relative to, and limited to, the
+/// directory of the configuration module.
+fn render_string<'lua>(
+    lua: &'lua Lua,
+    (text, vars): (String, Option<Table<'lua>>),
+) -> LuaResult<String> {
+    let dir = config::base_dir().map_err(|e| LuaError::runtime(format!("{e:#}")))?;
+    render(lua, "template", &text, vars, &dir, &dir)
+}
+
+/// Lua function to register a filter that can be used in templates as `value | name`.
+fn set_filter(lua: &Lua, (name, f): (String, Function)) -> LuaResult<()> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(LuaError::runtime(format!("invalid filter name `{name}`")));
+    }
+
+    let filters: Table = lua.named_registry_value(TEMPLATE_FILTERS)?;
+    filters.set(name, f)
+}
+
+/// Calls the filter `name` with the value and any extra arguments.
+fn apply_filter<'lua>(
+    lua: &'lua Lua,
+    (name, args): (String, MultiValue<'lua>),
+) -> LuaResult<Value<'lua>> {
+    let filters: Table = lua.named_registry_value(TEMPLATE_FILTERS)?;
+    match filters.get::<_, Option<Function>>(name.as_str())? {
+        Some(f) => f.call(args),
+        None => Err(LuaError::runtime(format!("unknown filter `{name}`"))),
+    }
+}
+
+/// Filter that replaces `nil` with a default value.
+fn default<'lua>(
+    _: &'lua Lua,
+    (value, default): (Value<'lua>, Value<'lua>),
+) -> LuaResult<Value<'lua>> {
+    Ok(if value.is_nil() { default } else { value })
+}
+
+/// Filter that joins a list of values with a separator.
+fn join(_: &Lua, (values, sep): (Vec<String>, Option<String>)) -> LuaResult<String> {
+    Ok(values.join(sep.as_deref().unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn render_filters_and_includes() {
+        let dir = TempDir::new("template");
+        std::fs::create_dir_all(dir.join("partials")).unwrap();
+        std::fs::write(dir.join("partials/_user.tmpl"), "{{ name | upper }}").unwrap();
+        std::fs::write(dir.join("loop"), "{{ include('loop') }}").unwrap();
+
+        let lua = crate::lua::create_state().unwrap();
+        lua.load("dfim.template.filter('shout', function(s, n) return s .. ('!'):rep(n) end)")
+            .exec()
+            .unwrap();
+        let vars = lua.create_table().unwrap();
+        vars.set("name", "jo").unwrap();
+        let text = "{{ include('partials/_user.tmpl') | shout(2) }} {{ missing | default('-') }}";
+        let out = render(&lua, "test", text, Some(vars), &dir, &dir);
+        let recursive = render(&lua, "test", "{{ include('loop') }}", None, &dir, &dir);
+
+        assert_eq!(out.unwrap(), "JO!! -");
+        assert!(recursive.is_err());
+        assert!(render(&lua, "test", "{{ 1 | nope }}", None, &dir, &dir).is_err());
+    }
+
+    #[test]
+    fn include_outside_root() {
+        let lua = crate::lua::create_state().unwrap();
+        let root = TempDir::new("template-root");
+        let dir = root.join("home");
+        for path in ["../../etc/passwd", "/etc/passwd"] {
+            let text = format!("{{{{ include('{path}') }}}}");
+            let err = render(&lua, "test", &text, None, &dir, &root).unwrap_err();
+            assert!(err.to_string().contains("cannot include"), "{err}");
+        }
+    }
+}
@@ -1,18 +1,36 @@
+mod archive;
+mod block;
 mod cli;
 mod commands;
 mod config;
+mod data;
+mod deploy;
+mod fetch;
+mod fs;
+mod generation;
+mod git;
+mod ignore;
+mod layer;
+mod lock;
 mod lua;
 #[macro_use]
 mod macros;
+mod plan;
+mod process;
 mod repl;
 mod source;
+mod state;
+mod status;
+mod template;
+#[cfg(test)]
+mod testing;
 
 use std::{
-    io::{stderr, IsTerminal},
+    io::{stderr, stdout, Write},
     process::ExitCode,
 };
 
-use clap::{ColorChoice, Parser};
+use clap::Parser;
 use fern::{
     colors::{Color, ColoredLevelConfig},
     Dispatch,
@@ -24,6 +42,8 @@ use crate::cli::Cli;
 fn main() -> ExitCode {
     match run() {
         Ok(_) => ExitCode::SUCCESS,
+        // output was closed early, e.g. when piped into `head`
+        Err(e) if is_broken_pipe(&e) => ExitCode::SUCCESS,
         Err(e) => {
             eprintln!("error: {e:?}");
             ExitCode::FAILURE
@@ -31,12 +51,24 @@ fn main() -> ExitCode {
     }
 }
 
+/// Returns `true` if `error` was caused by writing to a closed pipe.
+fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| {
+        e.downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe)
+    })
+}
+
 fn run() -> anyhow::Result<()> {
     let args = Cli::parse();
     setup_logger(&args)?;
     if let Some(path) = args.config_path.as_ref() {
         config::Config::set_override(path)?;
     }
+    lock::set_locked(args.locked);
+    if let Some(path) = args.answers.as_ref() {
+        data::set_answers_file(path)?;
+    }
 
     #[cfg(debug_assertions)]
     {
@@ -44,7 +76,7 @@ fn run() -> anyhow::Result<()> {
     }
 
     if args.version {
-        println!("{}", args.version_string());
+        writeln!(stdout().lock(), "{}", args.version_string())?;
         return Ok(());
     }
 
@@ -52,11 +84,7 @@ fn run() -> anyhow::Result<()> {
 }
 
 fn setup_logger(args: &Cli) -> anyhow::Result<()> {
-    let use_color = match args.color.unwrap_or(ColorChoice::Auto) {
-        ColorChoice::Auto => stderr().is_terminal(),
-        ColorChoice::Always => true,
-        ColorChoice::Never => false,
-    };
+    let use_color = args.use_color(&stderr());
 
     let level = if let Some(level) = args.log_level {
         level.to_level_filter()
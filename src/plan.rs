@@ -0,0 +1,1017 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block,
+    config::{data_dir, home_dir},
+    deploy::{self, display_path, ManagedFile},
+    fs,
+    layer::{ConflictPolicy, DeployMode, Layer},
+    process::{self, RunOptions},
+    state::{Entry, State},
+    status::{self, Status},
+    template,
+};
+
+/// A single filesystem or state change.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// Creates a directory and any missing parents, recording them in the state file.
+    CreateDir { path: PathBuf },
+    /// Atomically writes a file with content from `source`.
+    WriteFile {
+        path: PathBuf,
+        source: PathBuf,
+        hash: String,
+        /// Content to write, if it differs from the content of `source`.
+        #[serde(skip)]
+        content: Option<Vec<u8>>,
+    },
+    /// Inserts or updates the block `name` in `path` with content from `source`, keeping the rest
+    /// of the file.
+    WriteBlock {
+        path: PathBuf,
+        source: PathBuf,
+        name: String,
+        comment: String,
+        hash: String,
+        /// Content to write, if it differs from the content of `source`.
+        #[serde(skip)]
+        content: Option<Vec<u8>>,
+    },
+    /// Creates a symlink at `path` pointing to `target`.
+    CreateLink { path: PathBuf, target: PathBuf },
+    /// Removes a file or symlink.
+    Remove { path: PathBuf },
+    /// Removes the block `name` from `path`, and the file itself if nothing else is left.
+    RemoveBlock { path: PathBuf, name: String },
+    /// Moves an existing file, symlink, or directory to `backup`.
+    Backup { path: PathBuf, backup: PathBuf },
+    /// Replaces the content of `source` with the content of `path`.
+    Adopt { path: PathBuf, source: PathBuf },
+    /// Copies a file or symlink from `backup` to `path`, or moves it if it is a directory.
+    Restore { path: PathBuf, backup: PathBuf },
+    /// Removes a directory if it is empty, and from the state file once it is gone.
+    RemoveDir { path: PathBuf },
+    /// Sets the permission bits of a file (ignored on non-unix platforms).
+    Chmod { path: PathBuf, mode: u32 },
+    /// Runs an external command.
+    RunHook { command: Vec<String>, cwd: PathBuf },
+    /// Records a deployed path in the state file.
+    Record { path: PathBuf, entry: Entry },
+    /// Removes a path from the state file.
+    Forget { path: PathBuf },
+    /// Removes the block `name` in `path` from the state file.
+    ForgetBlock { path: PathBuf, name: String },
+}
+
+impl Operation {
+    /// Returns `true` if this operation only changes the state file.
+    pub fn is_state_only(&self) -> bool {
+        matches!(
+            self,
+            Operation::Record { .. } | Operation::Forget { .. } | Operation::ForgetBlock { .. }
+        )
+    }
+
+    /// Performs the operation, updating `state` as needed.
+    pub fn execute(&self, state: &mut State) -> Result<()> {
+        trace!("Executing: {self}");
+        match self {
+            Operation::CreateDir { path } => {
+                let created = path
+                    .ancestors()
+                    .take_while(|p| !p.exists())
+                    .map(Path::to_path_buf)
+                    .collect::<Vec<_>>();
+                std::fs::create_dir_all(path)
+                    .with_context(|| format!("failed to create directory `{}`", path.display()))?;
+                state.dirs.extend(created);
+            }
+            Operation::WriteFile {
+                path,
+                source,
+                content,
+                ..
+            } => {
+                let content = match content {
+                    Some(c) => c.to_owned(),
+                    None => std::fs::read(source)
+                        .with_context(|| format!("failed to read `{}`", source.display()))?,
+                };
+                fs::write_atomic(path, &content)?;
+            }
+            Operation::WriteBlock {
+                path,
+                source,
+                name,
+                comment,
+                content,
+                ..
+            } => {
+                let content = match content {
+                    Some(c) => c.to_owned(),
+                    None => std::fs::read(source)
+                        .with_context(|| format!("failed to read `{}`", source.display()))?,
+                };
+                let content = String::from_utf8(content)
+                    .with_context(|| format!("`{}` is not valid UTF-8", source.display()))?;
+                let text = match std::fs::read_to_string(path) {
+                    Ok(t) => t,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+                    Err(e) => {
+                        return Err(e)
+                            .with_context(|| format!("failed to read `{}`", path.display()))
+                    }
+                };
+                let text = block::insert(&text, name, comment, &content)
+                    .with_context(|| format!("failed to update block in `{}`", path.display()))?;
+                write_keeping_mode(path, text.as_bytes())?;
+            }
+            Operation::CreateLink { path, target } => deploy::symlink(target, path)
+                .with_context(|| format!("failed to create link `{}`", path.display()))?,
+            Operation::Remove { path } => std::fs::remove_file(path)
+                .with_context(|| format!("failed to remove `{}`", path.display()))?,
+            Operation::RemoveBlock { path, name } => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read `{}`", path.display()))?;
+                let Some(text) = block::remove(&text, name)
+                    .with_context(|| format!("failed to remove block from `{}`", path.display()))?
+                else {
+                    return Ok(());
+                };
+                if text.is_empty() {
+                    std::fs::remove_file(path)
+                        .with_context(|| format!("failed to remove `{}`", path.display()))?;
+                } else {
+                    write_keeping_mode(path, text.as_bytes())?;
+                }
+            }
+            Operation::Backup { path, backup } => fs::move_path(path, backup)?,
+            Operation::Adopt { path, source } => {
+                let content = std::fs::read(path)
+                    .with_context(|| format!("failed to read `{}`", path.display()))?;
+                fs::write_atomic(source, &content)?;
+            }
+            Operation::Restore { path, backup } => {
+                if backup.is_dir() {
+                    fs::move_path(backup, path)?;
+                } else {
+                    fs::copy_path(backup, path)?;
+                }
+            }
+            Operation::RemoveDir { path } => {
+                // directories may have gained files that were never managed
+                let mut entries = std::fs::read_dir(path)
+                    .with_context(|| format!("failed to read directory `{}`", path.display()))?;
+                if entries.next().is_none() {
+                    std::fs::remove_dir(path).with_context(|| {
+                        format!("failed to remove directory `{}`", path.display())
+                    })?;
+                    state.dirs.remove(path);
+                }
+            }
+            Operation::Chmod { path, mode } => set_mode(path, *mode)?,
+            Operation::RunHook { command, cwd } => {
+                let opts = RunOptions {
+                    cwd: Some(cwd.to_owned()),
+                    ..Default::default()
+                };
+                let output = process::run(command, &opts)?;
+                if !output.status.success() {
+                    bail!(
+                        "hook `{}` failed ({}):\n{}",
+                        command.join(" "),
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim_end()
+                    );
+                }
+            }
+            Operation::Record { path, entry } => state.insert(path, entry.clone()),
+            Operation::Forget { path } => {
+                state.entries.remove(path);
+            }
+            Operation::ForgetBlock { path, name } => state.remove_block(path, name),
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::CreateDir { path } => write!(f, "mkdir {}", display_path(path)),
+            Operation::WriteFile { path, source, .. } => write!(
+                f,
+                "write {} (from {})",
+                display_path(path),
+                display_path(source)
+            ),
+            Operation::WriteBlock {
+                path, source, name, ..
+            } => write!(
+                f,
+                "write block dfim:{name} in {} (from {})",
+                display_path(path),
+                display_path(source)
+            ),
+            Operation::CreateLink { path, target } => {
+                write!(f, "link {} -> {}", display_path(path), target.display())
+            }
+            Operation::Remove { path } => write!(f, "remove {}", display_path(path)),
+            Operation::RemoveBlock { path, name } => {
+                write!(f, "remove block dfim:{name} from {}", display_path(path))
+            }
+            Operation::Backup { path, backup } => write!(
+                f,
+                "backup {} to {}",
+                display_path(path),
+                display_path(backup)
+            ),
+            Operation::Adopt { path, source } => write!(
+                f,
+                "adopt {} into {}",
+                display_path(path),
+                display_path(source)
+            ),
+            Operation::Restore { path, backup } => write!(
+                f,
+                "restore {} from {}",
+                display_path(path),
+                display_path(backup)
+            ),
+            Operation::RemoveDir { path } => write!(f, "rmdir {}", display_path(path)),
+            Operation::Chmod { path, mode } => {
+                write!(f, "chmod {mode:o} {}", display_path(path))
+            }
+            Operation::RunHook { command, .. } => write!(f, "run `{}`", command.join(" ")),
+            Operation::Record { path, .. } => write!(f, "record {}", display_path(path)),
+            Operation::Forget { path } => write!(f, "forget {}", display_path(path)),
+            Operation::ForgetBlock { path, name } => {
+                write!(f, "forget block dfim:{name} in {}", display_path(path))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("failed to set permissions on `{}`", path.display()))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_: &Path, _: u32) -> Result<()> {
+    Ok(())
+}
+
+/// Atomically writes `content` to `path`, keeping the permissions of the file it replaces.
+fn write_keeping_mode(path: &Path, content: &[u8]) -> Result<()> {
+    let permissions = std::fs::metadata(path).ok().map(|m| m.permissions());
+    fs::write_atomic(path, content)?;
+    if let Some(p) = permissions {
+        std::fs::set_permissions(path, p)
+            .with_context(|| format!("failed to set permissions on `{}`", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Returns the permission bits of `source` if they should be copied to `target`.
+#[cfg(unix)]
+fn copied_mode(source: &Path, target: &Path) -> Result<Option<u32>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(source)?.permissions().mode() & 0o7777;
+    if mode & 0o111 == 0 {
+        return Ok(None);
+    }
+    match std::fs::symlink_metadata(target) {
+        Ok(m) if m.is_file() && m.permissions().mode() & 0o7777 == mode => Ok(None),
+        _ => Ok(Some(mode)),
+    }
+}
+
+#[cfg(not(unix))]
+fn copied_mode(_: &Path, _: &Path) -> Result<Option<u32>> {
+    Ok(None)
+}
+
+/// The kind of change a [`Step`] makes to its target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Create,
+    Update,
+    Adopt,
+    Restore,
+    Unchanged,
+    Skip,
+    Remove,
+    Forget,
+    Run,
+}
+
+impl Action {
+    /// Returns the past tense form used when reporting executed steps.
+    pub fn past_tense(&self) -> &'static str {
+        match self {
+            Action::Create => "created",
+            Action::Update => "updated",
+            Action::Adopt => "adopted",
+            Action::Restore => "restored",
+            Action::Unchanged => "unchanged",
+            Action::Skip => "skipped",
+            Action::Remove => "removed",
+            Action::Forget => "forgot",
+            Action::Run => "ran",
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Action::Create => "create",
+            Action::Update => "update",
+            Action::Adopt => "adopt",
+            Action::Restore => "restore",
+            Action::Unchanged => "unchanged",
+            Action::Skip => "skip",
+            Action::Remove => "remove",
+            Action::Forget => "forget",
+            Action::Run => "run",
+        };
+        f.pad(s)
+    }
+}
+
+/// A group of operations for a single target or hook.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Step {
+    pub action: Action,
+    /// Layer the step belongs to, which is `None` for directories shared by layers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub operations: Vec<Operation>,
+}
+
+impl Step {
+    pub fn new(action: Action, layer: &str, target: &Path) -> Self {
+        Self {
+            action,
+            layer: Some(layer.to_owned()),
+            target: Some(target.to_owned()),
+            reason: None,
+            operations: vec![],
+        }
+    }
+
+    /// Creates a step for a directory, which does not belong to any layer.
+    pub fn dir(action: Action, path: &Path) -> Self {
+        Self {
+            action,
+            layer: None,
+            target: Some(path.to_owned()),
+            reason: None,
+            operations: vec![],
+        }
+    }
+
+    pub fn with_reason<S: Into<String>>(mut self, reason: S) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+
+    /// Returns a short description of the step target for reports.
+    pub fn describe(&self) -> String {
+        match &self.target {
+            Some(t) => display_path(t),
+            None => format!(
+                "hook for layer `{}`",
+                self.layer.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// An ordered list of steps that can be inspected before being executed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Plan {
+    pub steps: Vec<Step>,
+}
+
+impl Plan {
+    /// Returns `true` if executing the plan would not change anything.
+    pub fn is_empty(&self) -> bool {
+        self.steps.iter().all(|s| s.operations.is_empty())
+    }
+
+    /// Executes every step in order, calling `report` after each step completes.
+    ///
+    /// Execution stops at the first failed operation. Changes made to `state` before the failure
+    /// are kept, so the caller should still save it.
+    pub fn execute<F: FnMut(&Step)>(&self, state: &mut State, mut report: F) -> Result<()> {
+        debug!("Executing plan with {} steps", self.steps.len());
+        for step in &self.steps {
+            for op in &step.operations {
+                op.execute(state)?;
+            }
+            report(step);
+        }
+
+        Ok(())
+    }
+}
+
+/// Incrementally builds a [`Plan`], avoiding duplicate directory creation.
+#[derive(Default)]
+struct Builder {
+    plan: Plan,
+    dirs: HashSet<PathBuf>,
+}
+
+impl Builder {
+    /// Adds a [`Operation::CreateDir`] for the parent of `path` if it does not exist.
+    fn parent_dir(&mut self, step: &mut Step, path: &Path) {
+        let Some(parent) = path.parent() else {
+            return;
+        };
+        if parent.is_dir() || !self.dirs.insert(parent.to_owned()) {
+            return;
+        }
+        step.operations.push(Operation::CreateDir {
+            path: parent.to_owned(),
+        });
+    }
+
+    fn push(&mut self, step: Step) {
+        self.plan.steps.push(step);
+    }
+}
+
+/// Options controlling how [`apply`] resolves conflicts.
+#[derive(Debug, Clone)]
+pub struct ApplyOptions {
+    /// Conflict policy for layers that do not set their own.
+    pub on_conflict: ConflictPolicy,
+    /// Conflict policy overriding every layer.
+    pub force_conflict: Option<ConflictPolicy>,
+    /// Directory where conflicting targets are moved with [`ConflictPolicy::Backup`].
+    pub backup_dir: PathBuf,
+}
+
+impl ApplyOptions {
+    fn policy(&self, file: &ManagedFile) -> ConflictPolicy {
+        self.force_conflict
+            .or(file.on_conflict)
+            .unwrap_or(self.on_conflict)
+    }
+}
+
+/// Returns the directory containing every backup directory.
+pub fn backups_dir() -> PathBuf {
+    data_dir().join("backups")
+}
+
+/// Returns a new timestamped directory path for backups. The directory is not created.
+pub fn new_backup_dir() -> PathBuf {
+    // colons are not valid in windows paths
+    let stamp = humantime::format_rfc3339_seconds(SystemTime::now())
+        .to_string()
+        .replace(':', "-");
+    let root = backups_dir();
+
+    let mut dir = root.join(&stamp);
+    let mut n = 1;
+    while dir.exists() {
+        dir = root.join(format!("{stamp}.{n}"));
+        n += 1;
+    }
+    dir
+}
+
+/// Returns the path inside of `backup_dir` where `target` is backed up.
+fn backup_path(backup_dir: &Path, target: &Path) -> PathBuf {
+    let rel = match target.strip_prefix(home_dir()) {
+        Ok(p) => p.to_owned(),
+        Err(_) => target
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect(),
+    };
+    backup_dir.join(rel)
+}
+
+/// Builds the plan for deploying `files` from `layers`.
+pub fn apply(
+    layers: &[Layer],
+    files: &[ManagedFile],
+    state: &State,
+    options: &ApplyOptions,
+) -> Result<Plan> {
+    let mut builder = Builder::default();
+    let mut changed = HashSet::new();
+
+    for file in files {
+        let status = status::check(file, state)?;
+        if status == Status::Conflict {
+            let policy = options.policy(file);
+            if let Some(step) = resolve_conflict(&mut builder, file, policy, options)? {
+                changed.insert(file.layer.as_str());
+                builder.push(step);
+            }
+            continue;
+        }
+
+        let mut step = match status {
+            Status::UpToDate => Step::new(Action::Unchanged, &file.layer, &file.target),
+            Status::Missing => Step::new(Action::Create, &file.layer, &file.target),
+            Status::SourceChanged => Step::new(Action::Update, &file.layer, &file.target),
+            Status::Modified => {
+                builder.push(
+                    Step::new(Action::Skip, &file.layer, &file.target)
+                        .with_reason("modified since last deploy"),
+                );
+                continue;
+            }
+            Status::Conflict | Status::Orphaned => unreachable!(),
+        };
+
+        let hash = match status {
+            Status::UpToDate => None,
+            _ => {
+                changed.insert(file.layer.as_str());
+                let exists = std::fs::symlink_metadata(&file.target).is_ok();
+                deploy_operations(&mut builder, &mut step, file, exists)?
+            }
+        };
+        let hash = match (file.mode, hash) {
+            (DeployMode::Copy, None) => Some(fs::hash(&deploy::desired_content(file)?)),
+            (DeployMode::Block, None) => Some(fs::hash(deploy::desired_block(file)?.as_bytes())),
+            (_, h) => h,
+        };
+
+        record(&mut step, file, state, hash);
+        builder.push(step);
+    }
+
+    for layer in layers {
+        if layer.hook.is_empty() || !changed.contains(layer.name.as_str()) {
+            continue;
+        }
+        builder.push(Step {
+            action: Action::Run,
+            layer: Some(layer.name.clone()),
+            target: None,
+            reason: None,
+            operations: vec![Operation::RunHook {
+                command: layer.hook.clone(),
+                cwd: home_dir().to_owned(),
+            }],
+        });
+    }
+
+    Ok(builder.plan)
+}
+
+/// Builds the plan for adopting every target of `files` that exists but is not managed by dfim,
+/// replacing each source with the content of its target.
+pub fn adopt(files: &[ManagedFile], state: &State) -> Result<Plan> {
+    let mut plan = Plan::default();
+    for file in files {
+        if status::check(file, state)? == Status::Conflict {
+            plan.steps.push(adopt_step(file)?);
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Adds a [`Operation::Record`] to `step` if the state entry for `file` would change.
+fn record(step: &mut Step, file: &ManagedFile, state: &State, hash: Option<String>) {
+    let entry = state.entry(file, hash);
+    if !state.get(file).is_some_and(|e| e.same_deployment(&entry)) {
+        step.operations.push(Operation::Record {
+            path: file.target.clone(),
+            entry,
+        });
+    }
+}
+
+/// Builds the step for a target that exists but is not managed by dfim.
+///
+/// Returns `None` if the target should be left untouched.
+fn resolve_conflict(
+    builder: &mut Builder,
+    file: &ManagedFile,
+    policy: ConflictPolicy,
+    options: &ApplyOptions,
+) -> Result<Option<Step>> {
+    let step = match policy {
+        ConflictPolicy::Abort => bail!(
+            "target `{}` already exists and is not managed by dfim (conflict policy is `abort`)",
+            file.target.display()
+        ),
+        ConflictPolicy::Skip => {
+            builder.push(
+                Step::new(Action::Skip, &file.layer, &file.target)
+                    .with_reason("exists and is not managed by dfim"),
+            );
+            return Ok(None);
+        }
+        ConflictPolicy::Backup => {
+            // moving the link away would leave the file it points to unmanaged
+            if file.mode == DeployMode::Block && file.target.is_symlink() {
+                bail!(
+                    "cannot write block to `{}`, target is a symlink (deploy to the file it points to instead)",
+                    file.target.display()
+                );
+            }
+            let backup = backup_path(&options.backup_dir, &file.target);
+            let mut step = Step::new(Action::Update, &file.layer, &file.target)
+                .with_reason(format!("backup to {}", display_path(&backup)));
+            step.operations.push(Operation::Backup {
+                path: file.target.clone(),
+                backup,
+            });
+            let hash = deploy_operations(builder, &mut step, file, false)?;
+            step.operations.push(Operation::Record {
+                path: file.target.clone(),
+                entry: State::default().entry(file, hash),
+            });
+            step
+        }
+        ConflictPolicy::Adopt => adopt_step(file)?,
+    };
+
+    Ok(Some(step))
+}
+
+/// Builds the step that replaces the source of `file` with its existing target.
+fn adopt_step(file: &ManagedFile) -> Result<Step> {
+    // the rendered target would replace the template
+    if template::is_template(&file.source_path) {
+        bail!(
+            "cannot adopt `{}`, source `{}` is a template",
+            file.target.display(),
+            file.source_path.display()
+        );
+    }
+    if file.mode == DeployMode::Block {
+        bail!(
+            "cannot adopt `{}`, adopt is not supported for block mode",
+            file.target.display()
+        );
+    }
+    if !file.target.is_file() {
+        bail!(
+            "cannot adopt `{}`, target is not a file",
+            file.target.display()
+        );
+    }
+    let mut step = Step::new(Action::Adopt, &file.layer, &file.target);
+    step.operations.push(Operation::Adopt {
+        path: file.target.clone(),
+        source: file.source_path.clone(),
+    });
+    let hash = match file.mode {
+        DeployMode::Block => unreachable!(),
+        DeployMode::Link => {
+            step.operations.push(Operation::Remove {
+                path: file.target.clone(),
+            });
+            step.operations.push(Operation::CreateLink {
+                path: file.target.clone(),
+                target: deploy::link_value(file),
+            });
+            None
+        }
+        DeployMode::Copy => Some(fs::hash_file(&file.target)?),
+    };
+    step.operations.push(Operation::Record {
+        path: file.target.clone(),
+        entry: State::default().entry(file, hash),
+    });
+    Ok(step)
+}
+
+/// Adds the operations that deploy `file` to `step`, returning the content hash for copies.
+///
+/// If the target `exists`, it is replaced.
+fn deploy_operations(
+    builder: &mut Builder,
+    step: &mut Step,
+    file: &ManagedFile,
+    exists: bool,
+) -> Result<Option<String>> {
+    if !exists {
+        builder.parent_dir(step, &file.target);
+    }
+
+    match file.mode {
+        DeployMode::Link => {
+            if exists {
+                step.operations.push(Operation::Remove {
+                    path: file.target.clone(),
+                });
+            }
+            step.operations.push(Operation::CreateLink {
+                path: file.target.clone(),
+                target: deploy::link_value(file),
+            });
+            Ok(None)
+        }
+        DeployMode::Copy => {
+            let content = deploy::desired_content(file)?;
+            let hash = fs::hash(&content);
+            let raw = std::fs::read(&file.source_path)
+                .with_context(|| format!("failed to read `{}`", file.source_path.display()))?;
+            step.operations.push(Operation::WriteFile {
+                path: file.target.clone(),
+                source: file.source_path.clone(),
+                hash: hash.clone(),
+                content: (raw != content).then_some(content),
+            });
+            if let Some(mode) = copied_mode(&file.source_path, &file.target)? {
+                step.operations.push(Operation::Chmod {
+                    path: file.target.clone(),
+                    mode,
+                });
+            }
+            Ok(Some(hash))
+        }
+        DeployMode::Block => {
+            let content = deploy::desired_content(file)?;
+            let hash = fs::hash(deploy::desired_block(file)?.as_bytes());
+            let raw = std::fs::read(&file.source_path)
+                .with_context(|| format!("failed to read `{}`", file.source_path.display()))?;
+            step.operations.push(Operation::WriteBlock {
+                path: file.target.clone(),
+                source: file.source_path.clone(),
+                name: file.layer.clone(),
+                comment: file
+                    .comment
+                    .clone()
+                    .unwrap_or_else(|| block::DEFAULT_COMMENT.to_owned()),
+                hash: hash.clone(),
+                content: (raw != content).then_some(content),
+            });
+            Ok(Some(hash))
+        }
+    }
+}
+
+/// Returns `true` if the deployed `target` still matches its state `entry`.
+fn is_unchanged(target: &Path, entry: &Entry) -> Result<bool> {
+    let meta = std::fs::symlink_metadata(target)?;
+    match entry.mode {
+        DeployMode::Link => Ok(meta.is_symlink() && deploy::links_to(target, &entry.source_path)?),
+        DeployMode::Copy => Ok(meta.is_file() && entry.hash == Some(fs::hash_file(target)?)),
+        DeployMode::Block => Ok(deploy::deployed_block(target, &entry.layer)?
+            .is_some_and(|b| entry.hash == Some(fs::hash(b.as_bytes())))),
+    }
+}
+
+/// Returns `true` if the target recorded by `entry` no longer exists.
+fn is_missing(target: &Path, entry: &Entry) -> Result<bool> {
+    match entry.mode {
+        DeployMode::Block => Ok(deploy::deployed_block(target, &entry.layer)?.is_none()),
+        _ => Ok(std::fs::symlink_metadata(target).is_err()),
+    }
+}
+
+/// Builds the plan for removing the targets recorded in `state` that match `filter`.
+///
+/// Targets modified since they were deployed are skipped and remain in the state file.
+/// Directories created by dfim are removed as well once nothing else is left in them.
+pub fn remove<F>(state: &State, filter: F) -> Result<Plan>
+where
+    F: Fn(&Path, &Entry) -> bool,
+{
+    let mut plan = Plan::default();
+    let mut removed = HashSet::new();
+
+    for (target, entry) in state.iter() {
+        if !filter(target, entry) {
+            continue;
+        }
+
+        let forget = match entry.mode {
+            DeployMode::Block => Operation::ForgetBlock {
+                path: target.clone(),
+                name: entry.layer.clone(),
+            },
+            _ => Operation::Forget {
+                path: target.clone(),
+            },
+        };
+        let step = if is_missing(target, entry)? {
+            let mut step = Step::new(Action::Forget, &entry.layer, target).with_reason("missing");
+            step.operations.push(forget);
+            removed.insert(target.clone());
+            step
+        } else if is_unchanged(target, entry)? {
+            let mut step = Step::new(Action::Remove, &entry.layer, target);
+            if entry.mode != DeployMode::Block {
+                removed.insert(target.clone());
+            }
+            step.operations.push(match entry.mode {
+                DeployMode::Block => Operation::RemoveBlock {
+                    path: target.clone(),
+                    name: entry.layer.clone(),
+                },
+                _ => Operation::Remove {
+                    path: target.clone(),
+                },
+            });
+            step.operations.push(forget);
+            step
+        } else {
+            Step::new(Action::Skip, &entry.layer, target).with_reason("modified since last deploy")
+        };
+        plan.steps.push(step);
+    }
+
+    // remove nested directories first
+    let mut dirs = state
+        .dirs
+        .iter()
+        .filter(|d| removed.iter().any(|p| p.starts_with(d)))
+        .collect::<Vec<_>>();
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+    for dir in dirs {
+        if !is_emptied(dir, &removed)? {
+            continue;
+        }
+        let mut step = Step::dir(Action::Remove, dir);
+        step.operations
+            .push(Operation::RemoveDir { path: dir.clone() });
+        plan.steps.push(step);
+        removed.insert(dir.clone());
+    }
+
+    Ok(plan)
+}
+
+/// Returns `true` if `dir` would be empty once every path in `removed` is removed.
+fn is_emptied(dir: &Path, removed: &HashSet<PathBuf>) -> Result<bool> {
+    if !dir.is_dir() {
+        return Ok(false);
+    }
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read directory `{}`", dir.display()))?
+    {
+        if !removed.contains(&entry?.path()) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn operation_json() {
+        let op = Operation::CreateLink {
+            path: "/home/a/.bashrc".into(),
+            target: "/home/a/dots/.bashrc".into(),
+        };
+        let value = serde_json::to_value(&op).unwrap();
+        assert_eq!(value["op"], "create_link");
+        assert_eq!(value["target"], "/home/a/dots/.bashrc");
+        assert_eq!(serde_json::from_value::<Operation>(value).unwrap(), op);
+    }
+
+    #[test]
+    fn write_file_skips_content() {
+        let op = Operation::WriteFile {
+            path: "a".into(),
+            source: "b".into(),
+            hash: "c".into(),
+            content: Some(b"foo".to_vec()),
+        };
+        let value = serde_json::to_value(&op).unwrap();
+        assert!(value.get("content").is_none());
+    }
+
+    #[test]
+    fn plan_is_empty() {
+        let mut plan = Plan::default();
+        plan.steps
+            .push(Step::new(Action::Skip, "foo", Path::new("bar")).with_reason("modified"));
+        assert!(plan.is_empty());
+        plan.steps[0]
+            .operations
+            .push(Operation::Forget { path: "bar".into() });
+        assert!(!plan.is_empty());
+    }
+
+    #[test]
+    fn backup_path_outside_home() {
+        let p = backup_path(Path::new("/backups/x"), Path::new("/etc/foo.conf"));
+        assert_eq!(p, PathBuf::from("/backups/x/etc/foo.conf"));
+    }
+
+    fn file(source_path: &str, mode: DeployMode) -> ManagedFile {
+        ManagedFile {
+            layer: "foo".into(),
+            source: "bar".into(),
+            source_path: source_path.into(),
+            target: "/home/a/a".into(),
+            mode,
+            link: Default::default(),
+            comment: None,
+            on_conflict: None,
+            priority: 0,
+            content: None,
+        }
+    }
+
+    fn options() -> ApplyOptions {
+        ApplyOptions {
+            on_conflict: ConflictPolicy::Backup,
+            force_conflict: None,
+            backup_dir: "/backups".into(),
+        }
+    }
+
+    #[test]
+    fn conflict_policy_precedence() {
+        let mut file = file("/dots/a", DeployMode::Link);
+        file.on_conflict = Some(ConflictPolicy::Skip);
+        let mut options = options();
+        assert_eq!(options.policy(&file), ConflictPolicy::Skip);
+        options.force_conflict = Some(ConflictPolicy::Adopt);
+        assert_eq!(options.policy(&file), ConflictPolicy::Adopt);
+        options.force_conflict = None;
+        file.on_conflict = None;
+        assert_eq!(options.policy(&file), ConflictPolicy::Backup);
+    }
+
+    #[test]
+    fn adopt_template_source() {
+        let mut file = file("/dots/a.tmpl", DeployMode::Copy);
+        file.content = Some(b"rendered".to_vec());
+        let mut builder = Builder::default();
+        let result = resolve_conflict(&mut builder, &file, ConflictPolicy::Adopt, &options());
+        assert!(result.unwrap_err().to_string().contains("template"));
+        assert!(builder.plan.steps.is_empty());
+    }
+
+    #[test]
+    fn backup_symlinked_block_target() {
+        let root = TempDir::new("plan-block");
+        let mut file = file("/dots/a", DeployMode::Block);
+        file.target = root.join(".bashrc");
+        std::fs::write(root.join("bashrc"), "").unwrap();
+        deploy::symlink(Path::new("bashrc"), &file.target).unwrap();
+
+        let mut builder = Builder::default();
+        let result = resolve_conflict(&mut builder, &file, ConflictPolicy::Backup, &options());
+        assert!(result.unwrap_err().to_string().contains("symlink"));
+        assert!(builder.plan.steps.is_empty());
+    }
+
+    #[test]
+    fn remove_created_dirs() {
+        let root = TempDir::new("plan");
+        let (outer, inner) = (root.join("a"), root.join("a/b"));
+        let target = inner.join("c");
+        std::fs::create_dir_all(&inner).unwrap();
+        std::fs::write(&target, "foo").unwrap();
+        std::fs::write(outer.join("unmanaged"), "").unwrap();
+
+        let mut f = file("/dots/c", DeployMode::Copy);
+        f.target = target.clone();
+        let mut state = State::default();
+        let entry = state.entry(&f, Some(fs::hash(b"foo")));
+        state.insert(&target, entry);
+        state.dirs.extend([outer.clone(), inner.clone()]);
+        let plan = remove(&state, |_, _| true);
+
+        let steps = plan.unwrap().steps;
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].operations[0], Operation::Remove { path: target });
+        assert_eq!(steps[1].operations, [Operation::RemoveDir { path: inner }]);
+        assert_eq!(steps[1].layer, None);
+    }
+}
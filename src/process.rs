@@ -0,0 +1,44 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+use anyhow::{bail, Context, Result};
+use log::trace;
+use serde::Deserialize;
+
+/// Options for running an external process.
+#[derive(Debug, Default, Deserialize)]
+pub struct RunOptions {
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub clear_env: Option<bool>,
+}
+
+/// Runs a command to completion, capturing `stdout` and `stderr`.
+///
+/// The first element of `args` is the program to execute.
+pub fn run<S: AsRef<str>>(args: &[S], opts: &RunOptions) -> Result<Output> {
+    let Some((prog, args)) = args.split_first() else {
+        bail!("cannot execute empty command");
+    };
+    let prog = prog.as_ref();
+
+    let mut cmd = Command::new(prog);
+    cmd.args(args.iter().map(AsRef::as_ref));
+    if let Some(cwd) = &opts.cwd {
+        cmd.current_dir(cwd);
+    }
+    if opts.clear_env.unwrap_or_default() {
+        cmd.env_clear();
+    }
+    cmd.envs(&opts.env);
+
+    trace!("Running command: {cmd:?}");
+    cmd.output()
+        .with_context(|| format!("failed to execute `{prog}`"))
+}
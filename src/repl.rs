@@ -94,11 +94,11 @@ impl Repl {
         Ok(())
     }
 
-    fn load(&self, value: String) -> Chunk {
+    fn load(&self, value: String) -> Chunk<'_, '_> {
         self.lua.load(value).set_name("stdin")
     }
 
-    fn load_lines(&self, lines: &[String]) -> Chunk {
+    fn load_lines(&self, lines: &[String]) -> Chunk<'_, '_> {
         self.lua.load(lines.join(" ")).set_name("stdin")
     }
 }
@@ -1,32 +1,236 @@
-use std::{fmt, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
 
-use anyhow::bail;
-use mlua::{Error as LuaError, FromLua, IntoLua, Lua, Result as LuaResult, Value};
+use anyhow::{bail, Result};
+use mlua::{Error as LuaError, FromLua, IntoLua, Lua, Result as LuaResult, Table, Value};
+
+use crate::{archive, config::expand_path, ignore::IgnoreRules, layer::check_relative};
+
+static URL_TEMPLATES: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// Shorthand prefixes available without any configuration. `gh` is also used for bare
+/// `owner/repo` values.
+const DEFAULT_URL_TEMPLATES: [(&str, &str); 2] = [
+    ("gh", "https://github.com/{}.git"),
+    ("gl", "https://gitlab.com/{}.git"),
+];
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Source {
-    Repo(String),
+    Repo(Repo),
     Directory(PathBuf),
+    /// A single local file.
+    File(PathBuf),
+    /// A tar, tar.gz, or zip archive given as a local path or `file://` URL.
+    Archive(String),
+}
+
+/// A git repository source, optionally pinned to a branch, tag, or commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repo {
+    pub url: String,
+    pub reference: Option<GitRef>,
+}
+
+impl From<&str> for Repo {
+    fn from(value: &str) -> Self {
+        Self {
+            url: value.to_owned(),
+            reference: None,
+        }
+    }
+}
+
+/// A git reference to check out instead of the default branch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GitRef {
+    Branch(String),
+    Tag(String),
+    Rev(String),
+}
+
+impl GitRef {
+    /// Returns the Lua table key used for this kind of reference.
+    pub fn key(&self) -> &'static str {
+        match self {
+            GitRef::Branch(_) => "branch",
+            GitRef::Tag(_) => "tag",
+            GitRef::Rev(_) => "rev",
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        match self {
+            GitRef::Branch(v) | GitRef::Tag(v) | GitRef::Rev(v) => v,
+        }
+    }
 }
 
 impl Source {
+    /// Returns a short name for the kind of source.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Source::Repo(_) => "repo",
+            Source::Directory(_) => "dir",
+            Source::File(_) => "file",
+            Source::Archive(_) => "archive",
+        }
+    }
+
+    /// Resolves local paths in the source with [`expand_path`], so they do not depend on the
+    /// working directory. Remote repo and archive URLs are unchanged.
+    pub fn resolve_paths(self) -> Result<Self> {
+        let source = match self {
+            Source::Directory(d) => Source::Directory(expand_path(&d.to_string_lossy())?),
+            Source::File(f) => Source::File(expand_path(&f.to_string_lossy())?),
+            Source::Archive(a) if !a.contains("://") => {
+                Source::Archive(expand_path(&a)?.to_string_lossy().into())
+            }
+            Source::Repo(mut r) if is_local_path(&r.url) => {
+                r.url = expand_path(&r.url)?.to_string_lossy().into();
+                Source::Repo(r)
+            }
+            other => other,
+        };
+        Ok(source)
+    }
+
     /// Returns the source name based on the value. This value might be an empty string.
     pub fn name(&self) -> String {
         match &self {
-            Source::Repo(r) => r.split('/').last().unwrap_or_default().into(),
-            Source::Directory(d) => match d.components().last() {
+            Source::Repo(r) => {
+                let url = r.url.trim_end_matches('/');
+                let name = url.rsplit(['/', ':']).next().unwrap_or_default();
+                name.strip_suffix(".git").unwrap_or(name).into()
+            }
+            Source::Directory(d) | Source::File(d) => match d.components().next_back() {
                 Some(std::path::Component::Normal(s)) => s.to_string_lossy().into(),
                 _ => String::new(),
             },
+            Source::Archive(a) => archive::stem(a.rsplit('/').next().unwrap_or_default()).into(),
         }
     }
 }
 
+/// Returns the URL or local path passed to `git clone` for a repo source.
+///
+/// URLs (including `file://` remotes and scp-like `user@host:path` values) are used as-is. Values
+/// such as `gl:owner/repo` are expanded with the matching URL template, see [`set_url_templates`].
+/// Local paths are resolved like other paths in the configuration, see [`expand_path`], and bare
+/// `owner/repo` shorthand uses the `gh` template. Any other value is an error.
+pub fn repo_url(repo: &str) -> Result<String> {
+    expand_url(repo, url_templates())
+}
+
+/// Adds URL templates for repo shorthand, replacing any default template with the same prefix.
+///
+/// Templates replace `{}` with the rest of the value, e.g. `work = 'git@git.example.com:{}.git'`
+/// expands `work:team/dotfiles`. A template without `{}` is used as a prefix.
+pub fn set_url_templates(templates: HashMap<String, String>) -> Result<()> {
+    for prefix in templates.keys() {
+        if prefix.is_empty()
+            || !prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("invalid URL template prefix `{prefix}`");
+        }
+    }
+
+    let mut all = default_url_templates();
+    all.extend(templates);
+    if URL_TEMPLATES.set(all).is_err() {
+        bail!("failed to set URL templates");
+    }
+    Ok(())
+}
+
+fn default_url_templates() -> HashMap<String, String> {
+    DEFAULT_URL_TEMPLATES
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn url_templates() -> &'static HashMap<String, String> {
+    URL_TEMPLATES.get_or_init(default_url_templates)
+}
+
+fn expand_url(repo: &str, templates: &HashMap<String, String>) -> Result<String> {
+    if repo.contains("://") {
+        return Ok(repo.to_owned());
+    }
+    if let Some((prefix, rest)) = repo.split_once(':') {
+        if let Some(template) = templates.get(prefix) {
+            return Ok(apply_template(template, rest));
+        }
+    }
+    if is_scp_like(repo) {
+        return Ok(repo.to_owned());
+    }
+
+    if is_local_path(repo) {
+        return Ok(expand_path(repo)?.to_string_lossy().into());
+    }
+
+    match (repo.split_once('/'), templates.get("gh")) {
+        (Some((owner, name)), Some(template))
+            if !owner.is_empty() && !name.is_empty() && !name.contains('/') =>
+        {
+            Ok(apply_template(template, repo))
+        }
+        _ => bail!(
+            "repo `{repo}` is not a URL, `owner/repo` shorthand, or local path (use `./{repo}` for a path relative to the config)"
+        ),
+    }
+}
+
+fn apply_template(template: &str, path: &str) -> String {
+    let mut path = path.trim_matches('/');
+    // avoid `.git.git` when the template already adds the suffix
+    if template.contains("{}.git") {
+        path = path.strip_suffix(".git").unwrap_or(path);
+    }
+    if template.contains("{}") {
+        template.replace("{}", path)
+    } else {
+        format!("{template}{path}")
+    }
+}
+
+/// Returns `true` if a repo value is an explicit local path rather than `owner/repo` shorthand.
+///
+/// Local repos must be absolute or start with `./`, `../`, `~`, or `$`, so that the meaning of a
+/// value never depends on the current directory.
+fn is_local_path(repo: &str) -> bool {
+    let path = Path::new(repo);
+    path.is_absolute()
+        || repo.starts_with(['~', '$'])
+        || matches!(
+            path.components().next(),
+            Some(Component::CurDir | Component::ParentDir)
+        )
+}
+
+/// Returns `true` for scp-like git remotes, e.g. `git@github.com:owner/repo.git`.
+fn is_scp_like(repo: &str) -> bool {
+    match repo.split_once(':') {
+        Some((host, _)) => host.contains('@') && !host.contains('/'),
+        None => false,
+    }
+}
+
 impl fmt::Display for Source {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self {
-            Source::Repo(r) => f.write_str(r),
-            Source::Directory(d) => write!(f, "{}", d.display()),
+            Source::Repo(r) => f.write_str(&r.url),
+            Source::Directory(d) | Source::File(d) => write!(f, "{}", d.display()),
+            Source::Archive(a) => f.write_str(a),
         }
     }
 }
@@ -38,7 +242,7 @@ impl FromStr for Source {
         if s.is_empty() || s.chars().all(char::is_whitespace) {
             bail!("value must not be empty or whitespace");
         }
-        Ok(Self::Repo(s.to_owned()))
+        Ok(Self::Repo(s.into()))
     }
 }
 
@@ -54,15 +258,25 @@ impl<'lua> FromLua<'lua> for Source {
             }
             Value::Table(ref t) => {
                 if let Ok(s) = t.get::<i32, mlua::String>(1) {
-                    return Self::from_lua(Value::String(s), _lua);
+                    let Self::Repo(mut repo) = Self::from_lua(Value::String(s), _lua)? else {
+                        unreachable!("strings are always repo sources")
+                    };
+                    repo.reference = git_ref(t)?;
+                    return Ok(Self::Repo(repo));
                 }
                 if let Ok(d) = t.get::<&str, String>("dir") {
                     return Ok(Self::Directory(PathBuf::from(d)));
                 }
+                if let Ok(f) = t.get::<&str, String>("file") {
+                    return Ok(Self::File(PathBuf::from(f)));
+                }
+                if let Ok(a) = t.get::<&str, String>("archive") {
+                    return Ok(Self::Archive(a));
+                }
                 Err(LuaError::FromLuaConversionError {
                     from: value.type_name(),
                     to: "Source",
-                    message: Some("expected [1] or `dir` key in table".into()),
+                    message: Some("expected [1], `dir`, `file`, or `archive` key in table".into()),
                 })
             }
             _ => Err(LuaError::FromLuaConversionError {
@@ -74,12 +288,44 @@ impl<'lua> FromLua<'lua> for Source {
     }
 }
 
+/// Reads the optional `branch`, `tag`, or `rev` key from a source table.
+fn git_ref(t: &Table) -> LuaResult<Option<GitRef>> {
+    let mut refs = vec![];
+    if let Some(v) = t.get::<_, Option<String>>("branch")? {
+        refs.push(GitRef::Branch(v));
+    }
+    if let Some(v) = t.get::<_, Option<String>>("tag")? {
+        refs.push(GitRef::Tag(v));
+    }
+    if let Some(v) = t.get::<_, Option<String>>("rev")? {
+        refs.push(GitRef::Rev(v));
+    }
+
+    if refs.len() > 1 {
+        return Err(LuaError::FromLuaConversionError {
+            from: "table",
+            to: "Source",
+            message: Some("only one of `branch`, `tag`, or `rev` may be set".into()),
+        });
+    }
+    Ok(refs.pop())
+}
+
 impl<'lua> IntoLua<'lua> for Source {
     fn into_lua(self, lua: &'lua Lua) -> LuaResult<Value<'lua>> {
         match self {
-            Source::Repo(s) => {
-                let s = lua.create_string(s)?;
-                Ok(Value::String(s))
+            Source::Repo(Repo {
+                url,
+                reference: None,
+            }) => Ok(Value::String(lua.create_string(url)?)),
+            Source::Repo(Repo {
+                url,
+                reference: Some(r),
+            }) => {
+                let t = lua.create_table()?;
+                t.set(1, url)?;
+                t.set(r.key(), r.value())?;
+                Ok(Value::Table(t))
             }
             Source::Directory(p) => {
                 let t = lua.create_table()?;
@@ -87,10 +333,115 @@ impl<'lua> IntoLua<'lua> for Source {
                 t.set("dir", Value::String(lua.create_string(s)?))?;
                 Ok(Value::Table(t))
             }
+            Source::File(p) => {
+                let t = lua.create_table()?;
+                t.set("file", p.to_string_lossy().to_string())?;
+                Ok(Value::Table(t))
+            }
+            Source::Archive(a) => {
+                let t = lua.create_table()?;
+                t.set("archive", a)?;
+                Ok(Value::Table(t))
+            }
         }
     }
 }
 
+/// A registered source along with options that apply to every kind of source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceSpec {
+    pub source: Source,
+    /// Directory inside of the source that files are deployed from.
+    pub subdir: Option<PathBuf>,
+    /// Glob patterns for files that are never deployed, see [`IgnoreRules`].
+    pub ignore: Vec<String>,
+    /// Precedence over other sources that provide the same target, higher values win.
+    pub priority: i64,
+}
+
+impl From<Source> for SourceSpec {
+    fn from(source: Source) -> Self {
+        Self {
+            source,
+            subdir: None,
+            ignore: vec![],
+            priority: 0,
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for SourceSpec {
+    fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> LuaResult<Self> {
+        let mut spec = Self::from(Source::from_lua(value.clone(), lua)?);
+        let Value::Table(t) = value else {
+            return Ok(spec);
+        };
+
+        if let Some(d) = t.get::<_, Option<String>>("subdir")? {
+            let d = PathBuf::from(d);
+            check_relative(&d).map_err(|e| spec_error(format!("invalid `subdir`: {e}")))?;
+            if let Source::File(_) = spec.source {
+                return Err(spec_error(
+                    "`subdir` cannot be used with file sources".into(),
+                ));
+            }
+            spec.subdir = Some(d);
+        }
+
+        spec.ignore = match t.get::<_, Value>("ignore")? {
+            Value::Nil => vec![],
+            Value::String(s) => vec![s.to_str()?.to_owned()],
+            Value::Table(t) => t.sequence_values().collect::<LuaResult<_>>()?,
+            other => {
+                return Err(spec_error(format!(
+                    "expected `ignore` to be a string or table, found {}",
+                    other.type_name()
+                )))
+            }
+        };
+        IgnoreRules::new(&spec.ignore).map_err(|e| spec_error(format!("{e:#}")))?;
+        spec.priority = t.get::<_, Option<i64>>("priority")?.unwrap_or_default();
+
+        Ok(spec)
+    }
+}
+
+impl<'lua> IntoLua<'lua> for SourceSpec {
+    fn into_lua(self, lua: &'lua Lua) -> LuaResult<Value<'lua>> {
+        let value = self.source.into_lua(lua)?;
+        if self.subdir.is_none() && self.ignore.is_empty() && self.priority == 0 {
+            return Ok(value);
+        }
+
+        let t = match value {
+            Value::Table(t) => t,
+            other => {
+                let t = lua.create_table()?;
+                t.set(1, other)?;
+                t
+            }
+        };
+        if let Some(d) = self.subdir {
+            t.set("subdir", d.to_string_lossy().to_string())?;
+        }
+        if !self.ignore.is_empty() {
+            t.set("ignore", self.ignore)?;
+        }
+        if self.priority != 0 {
+            t.set("priority", self.priority)?;
+        }
+        Ok(Value::Table(t))
+    }
+}
+
+fn spec_error(message: String) -> LuaError {
+    LuaError::FromLuaConversionError {
+        from: "table",
+        to: "SourceSpec",
+        message: Some(message),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -126,4 +477,113 @@ mod tests {
         let value: Source = call(&lua, "{ 'foo', file = 'bar', dir = 'baz' }");
         assert_eq!(value, Source::Repo("foo".into()))
     }
+
+    #[test]
+    fn from_table_file() {
+        let lua = Lua::new();
+        let value: Source = call(&lua, "{ file = 'foo/.vimrc' }");
+        assert_eq!(value, Source::File("foo/.vimrc".into()));
+        assert_eq!(value.name(), ".vimrc");
+    }
+
+    #[test]
+    fn from_table_archive() {
+        let lua = Lua::new();
+        let value: Source = call(&lua, "{ archive = 'file:///srv/dots-1.0.tar.gz' }");
+        assert_eq!(value, Source::Archive("file:///srv/dots-1.0.tar.gz".into()));
+        assert_eq!(value.name(), "dots-1.0");
+    }
+
+    #[test]
+    fn from_table_tag() {
+        let lua = Lua::new();
+        let value: Source = call(&lua, "{ 'foo', tag = 'v1.0' }");
+        assert_eq!(
+            value,
+            Source::Repo(Repo {
+                url: "foo".into(),
+                reference: Some(GitRef::Tag("v1.0".into())),
+            })
+        )
+    }
+
+    #[test]
+    fn from_table_multiple_refs() {
+        let lua = Lua::new();
+        let result: LuaResult<Source> = lua
+            .load("return { 'foo', branch = 'main', rev = 'abc' }")
+            .call(());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn spec_subdir_ignore() {
+        let lua = Lua::new();
+        let spec: SourceSpec = lua
+            .load("return { 'foo', subdir = 'home', ignore = { '*.md', 'ci/' } }")
+            .call(())
+            .unwrap();
+        assert_eq!(spec.source, Source::Repo("foo".into()));
+        assert_eq!(spec.subdir, Some("home".into()));
+        assert_eq!(spec.ignore, vec!["*.md", "ci/"]);
+    }
+
+    #[test]
+    fn spec_subdir_escape() {
+        let lua = Lua::new();
+        let result: LuaResult<SourceSpec> = lua.load("return { 'foo', subdir = '../x' }").call(());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn repo_url_shorthand() {
+        assert_eq!(
+            repo_url("org/dotfiles").unwrap(),
+            "https://github.com/org/dotfiles.git"
+        );
+        assert!(repo_url("dotfiles").is_err());
+    }
+
+    #[test]
+    fn repo_url_templates() {
+        let mut templates = default_url_templates();
+        templates.insert("work".into(), "git@git.example.com:{}.git".into());
+        templates.insert("srv".into(), "file:///srv/git/".into());
+        for (repo, url) in [
+            ("gh:org/dotfiles", "https://github.com/org/dotfiles.git"),
+            ("gl:org/dotfiles.git", "https://gitlab.com/org/dotfiles.git"),
+            ("work:team/dots", "git@git.example.com:team/dots.git"),
+            ("srv:dots.git", "file:///srv/git/dots.git"),
+            ("git@github.com:org/dots.git", "git@github.com:org/dots.git"),
+            // shorthand even when a matching directory exists in the working directory
+            ("src/lua", "https://github.com/src/lua.git"),
+        ] {
+            assert_eq!(expand_url(repo, &templates).unwrap(), url);
+        }
+    }
+
+    #[test]
+    fn repo_name() {
+        for repo in [
+            "org/dotfiles",
+            "gh:org/dotfiles",
+            "https://example.com/org/dotfiles.git",
+            "https://example.com/org/dotfiles/",
+            "git@example.com:dotfiles.git",
+        ] {
+            assert_eq!(Source::Repo(repo.into()).name(), "dotfiles");
+        }
+    }
+
+    #[test]
+    fn repo_url_unchanged() {
+        for url in [
+            "https://example.com/org/dotfiles.git",
+            "file:///srv/git/dotfiles",
+            "git@github.com:org/dotfiles.git",
+            "/srv/git/dotfiles",
+        ] {
+            assert_eq!(repo_url(url).unwrap(), url);
+        }
+    }
 }
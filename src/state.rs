@@ -0,0 +1,273 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JValue;
+
+use crate::{config::data_dir, deploy::ManagedFile, fs, layer::DeployMode};
+
+/// Current version of the state file format.
+///
+/// This must be incremented when the format changes in a way older versions cannot read, along
+/// with a migration of older state files in [`State::parse`].
+pub const STATE_VERSION: u32 = 1;
+
+/// A single path deployed by dfim.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub layer: String,
+    pub source: String,
+    /// Absolute path of the file inside of the source at the time it was deployed.
+    pub source_path: PathBuf,
+    pub mode: DeployMode,
+    /// Content hash of the deployed file for copies, or of the block body for blocks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(with = "timestamp")]
+    pub deployed_at: SystemTime,
+    #[serde(with = "timestamp")]
+    pub updated_at: SystemTime,
+}
+
+impl Entry {
+    /// Returns `true` if both entries describe the same deployment, ignoring timestamps.
+    pub fn same_deployment(&self, other: &Entry) -> bool {
+        self.layer == other.layer
+            && self.source == other.source
+            && self.source_path == other.source_path
+            && self.mode == other.mode
+            && self.hash == other.hash
+    }
+}
+
+/// Persistent record of every path deployed by dfim, stored in [`data_dir`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct State {
+    pub version: u32,
+    /// Deployed entries, keyed by absolute target path.
+    #[serde(default)]
+    pub entries: BTreeMap<PathBuf, Entry>,
+    /// Deployed blocks, keyed by absolute target path and then by block name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub blocks: BTreeMap<PathBuf, BTreeMap<String, Entry>>,
+    /// Directories created by dfim for deployed targets, which are removed once they are empty.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub dirs: BTreeSet<PathBuf>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            version: STATE_VERSION,
+            entries: BTreeMap::new(),
+            blocks: BTreeMap::new(),
+            dirs: BTreeSet::new(),
+        }
+    }
+}
+
+impl State {
+    /// Returns the path of the state file.
+    pub fn path() -> PathBuf {
+        data_dir().join("state.json")
+    }
+
+    /// Loads the state file, or returns an empty state if it does not exist.
+    pub fn load() -> Result<Self> {
+        Self::load_from(&Self::path())
+    }
+
+    fn load_from(path: &Path) -> Result<Self> {
+        if !path.is_file() {
+            debug!("No state file found at `{}`", path.display());
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read state file `{}`", path.display()))?;
+        Self::parse(&content)
+            .with_context(|| format!("failed to parse state file `{}`", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let value: JValue = serde_json::from_str(content)?;
+        let Some(version) = value.get("version").and_then(JValue::as_u64) else {
+            bail!("state file has no version");
+        };
+        let version =
+            u32::try_from(version).with_context(|| format!("invalid state version {version}"))?;
+        if version > STATE_VERSION {
+            bail!("state version {version} is newer than supported version {STATE_VERSION}");
+        }
+        if version < STATE_VERSION {
+            bail!("state version {version} is no longer supported");
+        }
+
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Writes the state file.
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        debug!("Saving state to `{}`", path.display());
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write_atomic(&path, content.as_bytes())
+    }
+
+    /// Returns every recorded entry with its target path, followed by every recorded block.
+    pub fn iter(&self) -> impl Iterator<Item = (&PathBuf, &Entry)> {
+        let blocks = self
+            .blocks
+            .iter()
+            .flat_map(|(path, blocks)| blocks.values().map(move |e| (path, e)));
+        self.entries.iter().chain(blocks)
+    }
+
+    /// Returns the entry recorded for the block `name` in `target`, if any.
+    pub fn block(&self, target: &Path, name: &str) -> Option<&Entry> {
+        self.blocks.get(target).and_then(|b| b.get(name))
+    }
+
+    /// Returns the entry recorded for `file`, which is its block entry for block mode.
+    pub fn get(&self, file: &ManagedFile) -> Option<&Entry> {
+        match file.mode {
+            DeployMode::Block => self.block(&file.target, &file.layer),
+            _ => self.entries.get(&file.target),
+        }
+    }
+
+    /// Records `entry` for `target`, as a block named after its layer for block mode.
+    pub fn insert(&mut self, target: &Path, entry: Entry) {
+        match entry.mode {
+            DeployMode::Block => {
+                let blocks = self.blocks.entry(target.to_owned()).or_default();
+                blocks.insert(entry.layer.clone(), entry);
+            }
+            _ => {
+                self.entries.insert(target.to_owned(), entry);
+            }
+        }
+    }
+
+    /// Removes the entry for the block `name` in `target`.
+    pub fn remove_block(&mut self, target: &Path, name: &str) {
+        if let Some(blocks) = self.blocks.get_mut(target) {
+            blocks.remove(name);
+            if blocks.is_empty() {
+                self.blocks.remove(target);
+            }
+        }
+    }
+
+    /// Creates the entry recording that `file` was deployed, optionally with the content hash
+    /// of a copy.
+    ///
+    /// The original deployment time is kept if `file` is already recorded.
+    pub fn entry(&self, file: &ManagedFile, hash: Option<String>) -> Entry {
+        let now = SystemTime::now();
+        let deployed_at = self.get(file).map(|e| e.deployed_at).unwrap_or(now);
+
+        Entry {
+            layer: file.layer.clone(),
+            source: file.source.clone(),
+            source_path: file.source_path.clone(),
+            mode: file.mode,
+            hash,
+            deployed_at,
+            updated_at: now,
+        }
+    }
+}
+
+/// Serializes [`SystemTime`] values as RFC 3339 timestamps.
+pub(crate) mod timestamp {
+    use std::time::SystemTime;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn format(value: SystemTime) -> String {
+        humantime::format_rfc3339_seconds(value).to_string()
+    }
+
+    pub fn serialize<S: Serializer>(value: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&format(*value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SystemTime, D::Error> {
+        let s = String::deserialize(d)?;
+        humantime::parse_rfc3339(&s).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_current() {
+        let state = State::parse(
+            r#"{
+                "version": 1,
+                "entries": {
+                    "/home/a/.bashrc": {
+                        "layer": "shell",
+                        "source": "dots",
+                        "source_path": "/home/a/dots/.bashrc",
+                        "mode": "link",
+                        "deployed_at": "2024-01-01T00:00:00Z",
+                        "updated_at": "2024-01-02T00:00:00Z"
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let entry = &state.entries[Path::new("/home/a/.bashrc")];
+        assert_eq!(entry.layer, "shell");
+        assert_eq!(entry.mode, DeployMode::Link);
+        assert_eq!(entry.hash, None);
+    }
+
+    #[test]
+    fn blocks_by_name() {
+        let entry = |layer: &str| Entry {
+            layer: layer.into(),
+            source: "dots".into(),
+            source_path: format!("/home/a/dots/{layer}.sh").into(),
+            mode: DeployMode::Block,
+            hash: Some("abc".into()),
+            deployed_at: SystemTime::UNIX_EPOCH,
+            updated_at: SystemTime::UNIX_EPOCH,
+        };
+        let target = Path::new("/home/a/.bashrc");
+        let mut state = State::default();
+        state.insert(target, entry("shell"));
+        state.insert(target, entry("work"));
+        assert!(state.entries.is_empty());
+        assert_eq!(state.iter().count(), 2);
+
+        let state = State::parse(&serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(state.block(target, "work"), Some(&entry("work")));
+
+        let mut state = state;
+        state.remove_block(target, "shell");
+        state.remove_block(target, "work");
+        assert!(state.blocks.is_empty());
+    }
+
+    #[test]
+    fn parse_invalid_version() {
+        assert!(State::parse(r#"{ "hashes": { "/home/a/.bashrc": "abc" } }"#).is_err());
+        assert!(State::parse(r#"{ "version": 0 }"#).is_err());
+        assert!(State::parse(r#"{ "version": 4294967297 }"#).is_err());
+    }
+
+    #[test]
+    fn parse_newer_version() {
+        assert!(State::parse(r#"{ "version": 999 }"#).is_err());
+    }
+}
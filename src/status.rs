@@ -0,0 +1,208 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+    deploy::{self, ManagedFile},
+    fs,
+    layer::DeployMode,
+    state::State,
+};
+
+/// Drift between a managed file, its deployed target, and the state file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// The target matches what would be deployed.
+    UpToDate,
+    /// The target does not exist, or does not contain its block.
+    Missing,
+    /// The target was changed since it was last deployed.
+    Modified,
+    /// The source was changed since the target was last deployed.
+    SourceChanged,
+    /// The target exists but was not deployed by dfim.
+    Conflict,
+    /// The target was deployed by dfim but is no longer managed by any layer.
+    Orphaned,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::UpToDate => "up to date",
+            Status::Missing => "missing",
+            Status::Modified => "modified",
+            Status::SourceChanged => "source changed",
+            Status::Conflict => "conflict",
+            Status::Orphaned => "orphaned",
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// The status of a single target path.
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetStatus {
+    pub target: PathBuf,
+    pub layer: String,
+    pub source: String,
+    pub status: Status,
+}
+
+/// Computes the status of every managed file, followed by orphaned entries in `state`.
+pub fn check_all(files: &[ManagedFile], state: &State) -> Result<Vec<TargetStatus>> {
+    let mut result = Vec::with_capacity(files.len());
+    for file in files {
+        result.push(TargetStatus {
+            target: file.target.clone(),
+            layer: file.layer.clone(),
+            source: file.source.clone(),
+            status: check(file, state)?,
+        });
+    }
+
+    let managed = files
+        .iter()
+        .map(|f| key(&f.target, f.mode, &f.layer))
+        .collect::<HashSet<_>>();
+    for (target, entry) in state.iter() {
+        if !managed.contains(&key(target, entry.mode, &entry.layer)) {
+            result.push(TargetStatus {
+                target: target.clone(),
+                layer: entry.layer.clone(),
+                source: entry.source.clone(),
+                status: Status::Orphaned,
+            });
+        }
+    }
+
+    Ok(result)
+}
+
+/// Identifies a deployed target, along with the block name for block mode.
+pub fn key<'a>(target: &'a Path, mode: DeployMode, layer: &'a str) -> (&'a Path, Option<&'a str>) {
+    (target, (mode == DeployMode::Block).then_some(layer))
+}
+
+/// Computes the status of a single managed file.
+pub fn check(file: &ManagedFile, state: &State) -> Result<Status> {
+    let meta = match std::fs::symlink_metadata(&file.target) {
+        Ok(m) => m,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Status::Missing),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read `{}`", file.target.display()))
+        }
+    };
+    let recorded = state.get(file).and_then(|e| e.hash.as_deref());
+
+    let status = match file.mode {
+        DeployMode::Link if meta.is_symlink() => {
+            if deploy::links_to(&file.target, &file.source_path)? {
+                Status::UpToDate
+            } else {
+                match state.get(file) {
+                    Some(e) if deploy::links_to(&file.target, &e.source_path)? => {
+                        Status::SourceChanged
+                    }
+                    Some(_) => Status::Modified,
+                    None => Status::Conflict,
+                }
+            }
+        }
+        DeployMode::Link if meta.is_file() => match recorded {
+            Some(h) if fs::hash_file(&file.target)? == h => Status::SourceChanged,
+            Some(_) => Status::Modified,
+            None => Status::Conflict,
+        },
+        DeployMode::Copy if meta.is_symlink() => {
+            if deploy::links_to(&file.target, &file.source_path)? {
+                Status::SourceChanged
+            } else {
+                Status::Conflict
+            }
+        }
+        DeployMode::Copy if meta.is_file() => {
+            let current = fs::hash_file(&file.target)?;
+            if current == fs::hash(&deploy::desired_content(file)?) {
+                Status::UpToDate
+            } else {
+                match recorded {
+                    Some(h) if h == current => Status::SourceChanged,
+                    Some(_) => Status::Modified,
+                    None => Status::Conflict,
+                }
+            }
+        }
+        DeployMode::Block if meta.is_file() => {
+            match deploy::deployed_block(&file.target, &file.layer)? {
+                None => Status::Missing,
+                Some(body) => {
+                    let current = fs::hash(body.as_bytes());
+                    if current == fs::hash(deploy::desired_block(file)?.as_bytes()) {
+                        Status::UpToDate
+                    } else {
+                        // blocks are always owned by dfim, even without a state entry
+                        match recorded {
+                            Some(h) if h != current => Status::Modified,
+                            _ => Status::SourceChanged,
+                        }
+                    }
+                }
+            }
+        }
+        _ => Status::Conflict,
+    };
+
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn check_links() {
+        let root = TempDir::new("status");
+        let (old, new, other) = (root.join("old"), root.join("new"), root.join("other"));
+        let target = root.join("home/.bashrc");
+        std::fs::create_dir_all(root.join("home")).unwrap();
+        deploy::symlink(Path::new("../new"), &target).unwrap();
+
+        let mut file = ManagedFile {
+            layer: "shell".into(),
+            source: "dots".into(),
+            source_path: new.clone(),
+            target: target.clone(),
+            mode: DeployMode::Link,
+            link: Default::default(),
+            comment: None,
+            on_conflict: None,
+            priority: 0,
+            content: None,
+        };
+        let mut state = State::default();
+        assert_eq!(check(&file, &state).unwrap(), Status::UpToDate);
+
+        file.source_path = other.clone();
+        assert_eq!(check(&file, &state).unwrap(), Status::Conflict);
+        file.source_path = new;
+        state.insert(&target, state.entry(&file, None));
+        file.source_path = other;
+        assert_eq!(check(&file, &state).unwrap(), Status::SourceChanged);
+        file.source_path = old;
+        state.insert(&target, state.entry(&file, None));
+        assert_eq!(check(&file, &state).unwrap(), Status::Modified);
+    }
+}
@@ -0,0 +1,306 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+/// Extension of source files that are rendered as templates when deployed.
+pub const EXTENSION: &str = "tmpl";
+
+/// File name prefix of partials, templates that are only rendered through `include`.
+///
+/// Partials such as `_header.tmpl` or `partials/_git-user.tmpl` are never deployed.
+pub const PARTIAL_PREFIX: &str = "_";
+
+/// Code run before every template, on the same line as the first line of the template so error
+/// line numbers match the template.
+const PRELUDE: &str = "local __buf = {} \
+    local function __out(v) if v ~= nil then __buf[#__buf + 1] = tostring(v) end end ";
+
+/// Returns `true` if `path` is a template.
+pub fn is_template(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == EXTENSION)
+}
+
+/// Returns `true` if `path` is a partial, see [`PARTIAL_PREFIX`].
+pub fn is_partial(path: &Path) -> bool {
+    is_template(path)
+        && path
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().starts_with(PARTIAL_PREFIX))
+}
+
+/// Returns the target path for a template, which is `path` without the template extension.
+pub fn strip_extension(path: &Path) -> PathBuf {
+    if is_template(path) {
+        path.with_extension("")
+    } else {
+        path.to_owned()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tag {
+    /// `{{ expr }}` writes the value of a lua expression.
+    Expr,
+    /// `{% code %}` runs lua statements, such as `if` or `for` blocks.
+    Block,
+    /// `{# text #}` is removed from the output.
+    Comment,
+}
+
+impl Tag {
+    const ALL: [(&'static str, &'static str, Tag); 3] = [
+        ("{{", "}}", Tag::Expr),
+        ("{%", "%}", Tag::Block),
+        ("{#", "#}", Tag::Comment),
+    ];
+}
+
+/// Compiles template text into a lua chunk that returns the rendered text.
+///
+/// Block and comment tags on a line of their own do not leave an empty line in the output, and a
+/// tag closed with `-}}` or `-%}` removes the line break that follows it. The generated chunk
+/// keeps the line numbers of the template, so errors point to the right line.
+pub fn compile(text: &str) -> Result<String> {
+    let mut chunk = String::from(PRELUDE);
+    let mut rest = text;
+    let mut line = 1;
+    // whether `rest` starts at the beginning of a line
+    let mut line_start = true;
+
+    loop {
+        let next = Tag::ALL
+            .iter()
+            .filter_map(|(open, close, tag)| rest.find(open).map(|i| (i, *open, *close, *tag)))
+            .min_by_key(|(i, ..)| *i);
+        let Some((i, open, close, tag)) = next else {
+            push_text(&mut chunk, rest);
+            break;
+        };
+
+        let mut before = &rest[..i];
+        let body_start = i + open.len();
+        let tag_line = line + before.matches('\n').count();
+        let Some(len) = rest[body_start..].find(close) else {
+            bail!("unclosed `{open}` on line {tag_line}");
+        };
+        let mut body = &rest[body_start..body_start + len];
+        // `-}}` and `-%}` remove the line break after the tag
+        let trim_after = tag != Tag::Comment && body.ends_with('-');
+        if trim_after {
+            body = &body[..body.len() - 1];
+        }
+        let mut after = &rest[body_start + len + close.len()..];
+        line = tag_line + body.matches('\n').count();
+
+        // a standalone tag removes its whole line, including the line break
+        let mut newline = false;
+        if trim_after {
+            if let Some(eol) = after
+                .strip_prefix("\r\n")
+                .or_else(|| after.strip_prefix('\n'))
+            {
+                after = eol;
+                newline = true;
+            }
+        } else if tag != Tag::Expr {
+            let start = before.rfind('\n').map(|n| n + 1);
+            let indent = &before[start.unwrap_or_default()..];
+            let eol = after
+                .strip_prefix("\r\n")
+                .or_else(|| after.strip_prefix('\n'));
+            if (start.is_some() || line_start) && indent.trim().is_empty() {
+                if let Some(eol) = eol {
+                    before = &before[..before.len() - indent.len()];
+                    after = eol;
+                    newline = true;
+                } else if after.is_empty() {
+                    before = &before[..before.len() - indent.len()];
+                }
+            }
+        }
+
+        push_text(&mut chunk, before);
+        match tag {
+            Tag::Expr => {
+                if body.trim().is_empty() {
+                    bail!("empty expression on line {tag_line}");
+                }
+                let expr = expression(body).with_context(|| format!("on line {tag_line}"))?;
+                chunk.push_str(&format!("__out({expr}) "));
+            }
+            Tag::Block => {
+                chunk.push_str(body);
+                chunk.push(' ');
+            }
+            Tag::Comment => chunk.push_str(&"\n".repeat(body.matches('\n').count())),
+        }
+        if newline {
+            chunk.push('\n');
+            line += 1;
+        }
+
+        line_start = rest[..rest.len() - after.len()].ends_with('\n');
+        rest = after;
+    }
+
+    chunk.push_str(" return table.concat(__buf)");
+    Ok(chunk)
+}
+
+/// Compiles the body of an expression tag, applying filters separated by `|`.
+///
+/// `value | name` calls the filter registered as `name` with the value, and `value | name(a, b)`
+/// passes extra arguments, e.g. `{{ email | default('none') | upper }}`.
+fn expression(body: &str) -> Result<String> {
+    let mut parts = split_pipes(body).into_iter();
+    let first = parts.next().unwrap_or_default();
+    if parts.len() == 0 {
+        return Ok(body.to_owned());
+    }
+    let mut expr = first.trim().to_owned();
+    if expr.is_empty() {
+        bail!("expression before `|` must not be empty");
+    }
+
+    for filter in parts {
+        let filter = filter.trim();
+        let end = filter
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(filter.len());
+        let (name, args) = filter.split_at(end);
+        let args = args.trim();
+        if name.is_empty() {
+            bail!("invalid filter `{filter}`");
+        }
+        expr = match args.strip_prefix('(').and_then(|a| a.strip_suffix(')')) {
+            _ if args.is_empty() => format!("__filter(\"{name}\", {expr})"),
+            Some(a) if a.trim().is_empty() => format!("__filter(\"{name}\", {expr})"),
+            Some(a) => format!("__filter(\"{name}\", {expr}, {a})"),
+            None => bail!("invalid filter `{filter}`"),
+        };
+    }
+
+    // keep the line count of the template
+    let lines = body.matches('\n').count() - expr.matches('\n').count();
+    expr.push_str(&"\n".repeat(lines));
+    Ok(expr)
+}
+
+/// Splits `expr` on `|` characters outside of strings and brackets.
+fn split_pipes(expr: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (i, c) in expr.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => match c {
+                '\'' | '"' => quote = Some(c),
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => depth = depth.saturating_sub(1),
+                '|' if depth == 0 => {
+                    parts.push(&expr[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
+            },
+        }
+    }
+
+    parts.push(&expr[start..]);
+    parts
+}
+
+/// Appends code that writes `text` to the output.
+///
+/// Line breaks are written as an escaped newline, which keeps the line numbers of the chunk.
+fn push_text(chunk: &mut String, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+
+    chunk.push_str("__out(\"");
+    for c in text.chars() {
+        match c {
+            '\\' => chunk.push_str("\\\\"),
+            '"' => chunk.push_str("\\\""),
+            '\n' => chunk.push_str("\\\n"),
+            '\r' => chunk.push_str("\\r"),
+            c if c.is_ascii_control() => chunk.push_str(&format!("\\{:03}", c as u32)),
+            c => chunk.push(c),
+        }
+    }
+    chunk.push_str("\") ");
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::Lua;
+
+    use super::*;
+
+    fn render(text: &str) -> String {
+        let lua = Lua::new();
+        lua.globals().set("name", "dfim").unwrap();
+        lua.load(compile(text).unwrap()).call(()).unwrap()
+    }
+
+    #[test]
+    fn render_expressions() {
+        assert_eq!(render("hello {{ name }}!"), "hello dfim!");
+        assert_eq!(render("{{ 1 + 2 }} {{ nil }}\"\\"), "3 \"\\");
+    }
+
+    #[test]
+    fn render_blocks() {
+        let text = "a\n{% for i = 1, 2 do %}\n  {{ i }}\n{% end %}\nb\n";
+        assert_eq!(render(text), "a\n  1\n  2\nb\n");
+        assert_eq!(render("{# note #}\nx {# y #}z"), "x z");
+        assert_eq!(render("{{ name -}}\n!\n"), "dfim!\n");
+    }
+
+    #[test]
+    fn compile_keeps_line_numbers() {
+        let chunk = compile("a\n{% x %}\n{# \n #}\n{{ y }}\n").unwrap();
+        let line = chunk.lines().position(|l| l.contains("__out( y )"));
+        assert_eq!(line, Some(4));
+        assert!(compile("a {{ b").is_err());
+    }
+
+    #[test]
+    fn compile_filters() {
+        assert_eq!(expression(" name ").unwrap(), " name ");
+        assert_eq!(
+            expression(" name | upper ").unwrap(),
+            "__filter(\"upper\", name)"
+        );
+        assert_eq!(
+            expression("'a|b' | default('x') | join(', ')").unwrap(),
+            "__filter(\"join\", __filter(\"default\", 'a|b', 'x'), ', ')"
+        );
+        assert_eq!(expression("f(a | b)").unwrap(), "f(a | b)");
+        assert!(expression("a | ").is_err());
+        assert!(expression("a | b c").is_err());
+    }
+
+    #[test]
+    fn template_target() {
+        assert!(is_template(Path::new(".gitconfig.tmpl")));
+        assert_eq!(
+            strip_extension(Path::new(".config/a.toml.tmpl")),
+            PathBuf::from(".config/a.toml")
+        );
+        assert_eq!(
+            strip_extension(Path::new(".bashrc")),
+            PathBuf::from(".bashrc")
+        );
+        assert!(is_partial(Path::new("partials/_user.tmpl")));
+        assert!(!is_partial(Path::new("_vimrc")));
+    }
+}